@@ -3,11 +3,31 @@ use std::env;
 use std::path::PathBuf;
 
 fn main() {
-    extension!(kvstore_app, js = ["src/runtime.js"]);
+    extension!(
+        kvstore_app,
+        js = ["src/runtime.js"],
+        esm_entry_point = "ext:kvstore_app/globals.js",
+        esm = [
+            dir "src/std",
+            "globals.js",
+            "v1/store.js",
+            "v1/context.js",
+            "v1/crypto.js",
+            "v1/encoding.js",
+        ],
+    );
 
     let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
     let snapshot_path = out_dir.join("RUNJS_SNAPSHOT.bin");
 
+    // Must match `V8_FLAGS` in `src/runtime.rs`, as V8 rejects a snapshot
+    // created with different flags.
+    deno_core::v8_set_flags(vec![
+        String::new(),
+        "--disallow-code-generation-from-strings".to_string(),
+        "--enable-experimental-regexp-engine-on-excessive-backtracks".to_string(),
+    ]);
+
     let snapshot = deno_core::snapshot::create_snapshot(
         deno_core::snapshot::CreateSnapshotOptions {
             cargo_manifest_dir: env!("CARGO_MANIFEST_DIR"),
@@ -0,0 +1,11 @@
+import { context } from "comet:context";
+import { kv } from "./lib/kv.ts";
+
+const { key } = context.getRequest<{ key: string }>();
+
+const value = await kv.delete(key);
+
+context.emit({
+  type: "kv-delete",
+  attributes: [{ key, value: value ?? "", index: false }],
+});
@@ -1,5 +1,8 @@
+import { context } from "comet:context";
+import { kv } from "./lib/kv.ts";
+
 const { key } = context.getRequest<{ key: string }>();
 
-const value = await store.get(key);
+const value = await kv.get(key);
 
 context.respond({ value });
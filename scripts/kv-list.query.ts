@@ -0,0 +1,17 @@
+import { context } from "comet:context";
+import { kv } from "./lib/kv.ts";
+
+const { prefix, start, end, limit, reverse } = context.getRequest<{
+  prefix?: string;
+  start?: string;
+  end?: string;
+  limit?: number;
+  reverse?: boolean;
+}>();
+
+const entries = [];
+for await (const entry of kv.list({ prefix, start, end, limit, reverse })) {
+  entries.push(entry);
+}
+
+context.respond({ entries });
@@ -1,3 +1,6 @@
+import { context } from "comet:context";
+import { store } from "comet:store";
+
 const { key, value } = context.getRequest<{ key: string; value: string }>();
 
 await store.set(key, value);
@@ -0,0 +1,4 @@
+import { store } from "comet:store";
+
+/** Keys of the key/value scripts, which all live in the namespace of `kv-set`. */
+export const kv = store.namespace("kv-set");
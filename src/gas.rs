@@ -0,0 +1,103 @@
+//! Gas accounting of script runs.
+//!
+//! Every run is given a gas limit and charged for starting up, for the size of
+//! its script, for each store access and emitted event in proportion to the
+//! bytes involved, and for every loop iteration and function call, see
+//! `meter`. Store costs mirror the Cosmos SDK KV store defaults.
+
+use std::fmt::Display;
+
+/// Gas limit of a transaction that does not set `gas_limit`.
+pub const DEFAULT_TX_GAS_LIMIT: u64 = 1_000_000;
+/// Largest `gas_limit` a transaction may set; larger ones are rejected.
+pub const MAX_TX_GAS_LIMIT: u64 = 100_000_000;
+/// Gas limit of every query.
+pub const QUERY_GAS_LIMIT: u64 = 10_000_000;
+
+pub const EXECUTION_FLAT: u64 = 10_000;
+pub const SOURCE_PER_BYTE: u64 = 1;
+pub const READ_FLAT: u64 = 1_000;
+pub const READ_PER_BYTE: u64 = 3;
+pub const WRITE_FLAT: u64 = 2_000;
+pub const WRITE_PER_BYTE: u64 = 30;
+pub const DELETE_FLAT: u64 = 1_000;
+pub const ITER_NEXT_FLAT: u64 = 30;
+pub const EVENT_FLAT: u64 = 500;
+pub const EVENT_PER_BYTE: u64 = 10;
+pub const HASH_FLAT: u64 = 300;
+pub const HASH_PER_BYTE: u64 = 3;
+pub const TICK: u64 = 1;
+
+/// Error of a run that used more gas than its limit.
+#[derive(Debug, Clone)]
+pub struct OutOfGas {
+    pub descriptor: String,
+    pub limit: u64,
+}
+
+impl Display for OutOfGas {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "out of gas in {}: limit {}", self.descriptor, self.limit)
+    }
+}
+
+impl std::error::Error for OutOfGas {}
+
+#[derive(Debug, Clone)]
+pub struct GasMeter {
+    limit: u64,
+    used: u64,
+    /// Set once a charge exceeded the limit. Kept so a script catching the
+    /// error still fails once it completes.
+    out_of_gas: Option<OutOfGas>,
+}
+
+impl GasMeter {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: 0,
+            out_of_gas: None,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(u64::MAX)
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Charges `amount`, failing if it goes past the limit, in which case the
+    /// whole limit counts as used.
+    pub fn consume(&mut self, amount: u64, descriptor: &str) -> Result<(), OutOfGas> {
+        if let Some(err) = &self.out_of_gas {
+            return Err(err.clone());
+        }
+
+        match self.used.checked_add(amount) {
+            Some(used) if used <= self.limit => {
+                self.used = used;
+                Ok(())
+            }
+            _ => {
+                self.used = self.limit;
+                let err = OutOfGas {
+                    descriptor: descriptor.to_string(),
+                    limit: self.limit,
+                };
+                self.out_of_gas = Some(err.clone());
+                Err(err)
+            }
+        }
+    }
+
+    pub fn out_of_gas(&self) -> Option<&OutOfGas> {
+        self.out_of_gas.as_ref()
+    }
+}
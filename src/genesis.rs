@@ -0,0 +1,100 @@
+//! Genesis state applied on `InitChain`.
+//!
+//! `app_state` of the genesis file is a JSON object of the following shape,
+//! where every field is optional:
+//!
+//! ```json
+//! {
+//!   "state": { "kv-set": { "name": "eddy" } },
+//!   "params": { "kv-set": { "max_length": 64 } }
+//! }
+//! ```
+//!
+//! `state` holds the initial values of each script namespace and `params` the
+//! parameters each script reads back with `context.getParams`. Both, along
+//! with the chain id, are written to the store under the reserved `.chain/`
+//! prefix, which no script namespace can collide with since script names
+//! never contain a dot, and `state` namespaces are held to the same rule. The
+//! whole object is also the request of the optional `init.ts` script.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+/// Script run once on `InitChain`, relative to the scripts directory.
+pub const INIT_SCRIPT: &str = "init.ts";
+/// Sender the init script sees.
+pub const GENESIS_SENDER: &str = "<genesis>";
+pub const CHAIN_ID_KEY: &[u8] = b".chain/chain_id";
+const PARAMS_PREFIX: &str = ".chain/params/";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Genesis {
+    /// Initial values, by namespace and key.
+    pub state: HashMap<String, BTreeMap<String, String>>,
+    /// Parameters, by script name.
+    pub params: HashMap<String, serde_json::Value>,
+}
+
+impl Genesis {
+    /// Parses `app_state`, an empty one standing for an empty genesis.
+    pub fn parse(app_state: &[u8]) -> anyhow::Result<(Self, serde_json::Value)> {
+        if app_state.is_empty() {
+            return Ok((Self::default(), serde_json::Value::Null));
+        }
+        let value: serde_json::Value = serde_json::from_slice(app_state)?;
+        let genesis: Self = serde_json::from_value(value.clone())?;
+        for namespace in genesis.state.keys() {
+            check_namespace(namespace)?;
+        }
+        Ok((genesis, value))
+    }
+}
+
+/// Fails unless `namespace` could be the name of a script, so genesis state
+/// lands neither under `.chain/` nor inside another namespace.
+fn check_namespace(namespace: &str) -> anyhow::Result<()> {
+    if namespace.is_empty() || namespace.contains(['.', '/']) {
+        anyhow::bail!("invalid genesis namespace {:?}", namespace);
+    }
+    Ok(())
+}
+
+/// Store key of the parameters of `script`.
+pub fn params_key(script: &str) -> Vec<u8> {
+    format!("{}{}", PARAMS_PREFIX, script).into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let (genesis, request) = Genesis::parse(b"").unwrap();
+        assert!(genesis.state.is_empty() && genesis.params.is_empty());
+        assert_eq!(request, serde_json::Value::Null);
+
+        let app_state = br#"{"state":{"kv-set":{"name":"eddy"}},"params":{"kv-set":1}}"#;
+        let (genesis, request) = Genesis::parse(app_state).unwrap();
+        assert_eq!(genesis.state["kv-set"]["name"], "eddy");
+        assert_eq!(genesis.params["kv-set"], serde_json::json!(1));
+        assert_eq!(
+            request,
+            serde_json::from_slice::<serde_json::Value>(app_state).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_namespaces() {
+        for namespace in ["", ".chain", "kv.set", "kv/set"] {
+            let app_state = serde_json::json!({ "state": { namespace: { "a": "1" } } });
+            assert!(
+                Genesis::parse(app_state.to_string().as_bytes()).is_err(),
+                "{:?} was accepted",
+                namespace
+            );
+        }
+    }
+}
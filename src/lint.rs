@@ -0,0 +1,201 @@
+//! Load-time check that scripts stay clear of nondeterministic JavaScript.
+//!
+//! The runtime already removes or stubs most of these globals, but rejecting
+//! them when the node starts points at the offending line instead of failing
+//! transactions once the node is in consensus.
+
+use std::{fmt::Display, path::Path};
+
+use deno_ast::{
+    swc::{
+        ast::{Expr, MemberExpr, MemberProp},
+        visit::{Visit, VisitWith},
+    },
+    MediaType, ParseParams, SourceRangedForSpanned, SourceTextInfo,
+};
+
+/// Globals whose behavior depends on the garbage collector, timers, other
+/// threads or the host locale.
+const BANNED_GLOBALS: &[&str] = &[
+    "WeakRef",
+    "FinalizationRegistry",
+    "SharedArrayBuffer",
+    "setTimeout",
+    "setInterval",
+    "Intl",
+];
+
+/// Members banned on any object, as `(object, member)`, `None` matching any
+/// object.
+const BANNED_MEMBERS: &[(Option<&str>, &str)] = &[
+    (Some("Atomics"), "wait"),
+    (Some("Atomics"), "waitAsync"),
+    (None, "toLocaleString"),
+    (None, "toLocaleDateString"),
+    (None, "toLocaleTimeString"),
+    (None, "localeCompare"),
+];
+
+/// `Math` functions whose results may differ in the last bits across
+/// platforms, banned when `LintOptions::float` is set.
+const FLOAT_FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "asin", "acos", "atan", "atan2", "sinh", "cosh", "tanh", "asinh", "acosh",
+    "atanh", "exp", "expm1", "log", "log1p", "log2", "log10", "pow", "cbrt", "hypot",
+];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LintOptions {
+    /// Also reject transcendental `Math` functions.
+    pub float: bool,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.path, self.line, self.column, self.message
+        )
+    }
+}
+
+/// Parses the script at `path` and returns every banned construct it uses.
+pub fn lint(path: &Path, options: LintOptions) -> anyhow::Result<Vec<Diagnostic>> {
+    let specifier = deno_core::ModuleSpecifier::from_file_path(std::fs::canonicalize(path)?)
+        .map_err(|_| anyhow::anyhow!("invalid script path {}", path.display()))?;
+    let parsed = deno_ast::parse_module(ParseParams {
+        specifier,
+        media_type: MediaType::from_path(path),
+        capture_tokens: false,
+        scope_analysis: false,
+        maybe_syntax: None,
+        text: SourceTextInfo::from_string(std::fs::read_to_string(path)?).text(),
+    })?;
+
+    let mut visitor = Linter {
+        path: path.display().to_string(),
+        text_info: parsed.text_info_lazy(),
+        options,
+        diagnostics: vec![],
+    };
+    parsed.module().visit_with(&mut visitor);
+    Ok(visitor.diagnostics)
+}
+
+struct Linter<'a> {
+    path: String,
+    text_info: &'a SourceTextInfo,
+    options: LintOptions,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, node: &impl SourceRangedForSpanned, message: String) {
+        let position = self.text_info.line_and_column_display(node.start());
+        self.diagnostics.push(Diagnostic {
+            path: self.path.clone(),
+            line: position.line_number,
+            column: position.column_number,
+            message,
+        });
+    }
+
+    fn check_member(&mut self, member: &MemberExpr) {
+        let MemberProp::Ident(prop) = &member.prop else {
+            return;
+        };
+        let prop = prop.sym.as_ref();
+        let object = match member.obj.as_ref() {
+            Expr::Ident(ident) => Some(ident.sym.as_ref()),
+            _ => None,
+        };
+
+        // `globalThis.setTimeout` is the same as `setTimeout`.
+        if object == Some("globalThis") && BANNED_GLOBALS.contains(&prop) {
+            return self.report(member, format!("`{}` is not deterministic", prop));
+        }
+        let banned = BANNED_MEMBERS
+            .iter()
+            .any(|&(o, p)| p == prop && (o.is_none() || o == object));
+        if banned {
+            return self.report(member, format!("`{}` is not deterministic", prop));
+        }
+        if self.options.float && object == Some("Math") && FLOAT_FUNCTIONS.contains(&prop) {
+            self.report(
+                member,
+                format!("`Math.{}` may differ across platforms", prop),
+            );
+        }
+    }
+}
+
+impl Visit for Linter<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Ident(ident) if BANNED_GLOBALS.contains(&ident.sym.as_ref()) => {
+                self.report(ident, format!("`{}` is not deterministic", ident.sym));
+            }
+            Expr::Member(member) => self.check_member(member),
+            _ => {}
+        }
+        expr.visit_children_with(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn lint_source(name: &str, source: &str, options: LintOptions) -> Vec<Diagnostic> {
+        let dir = TempDir::new("lint");
+        let path = dir.join(name);
+        std::fs::write(&path, source).unwrap();
+        lint(&path, options).unwrap()
+    }
+
+    #[test]
+    fn test_lint() {
+        let source = "setTimeout(() => {}, 0);\n\
+                      const intl = globalThis.Intl;\n\
+                      Atomics.wait(buf, 0, 0);\n";
+        let diagnostics = lint_source("banned.ts", source, LintOptions::default());
+
+        let positions = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1, "`setTimeout` is not deterministic"),
+                (2, 14, "`Intl` is not deterministic"),
+                (3, 1, "`wait` is not deterministic"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_float() {
+        let source = "export const x = Math.sin(1);\n";
+
+        let diagnostics = lint_source("float.ts", source, LintOptions::default());
+        assert!(diagnostics.is_empty());
+
+        let diagnostics = lint_source("float.ts", source, LintOptions { float: true });
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (1, 18));
+        assert_eq!(
+            diagnostics[0].message,
+            "`Math.sin` may differ across platforms"
+        );
+    }
+}
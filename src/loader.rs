@@ -1,11 +1,237 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail};
 use deno_ast::MediaType;
 use deno_ast::ParseParams;
 use deno_ast::SourceTextInfo;
 use deno_core::error::AnyError;
 use deno_core::ModuleLoadResponse;
 use deno_core::ModuleSourceCode;
+use deno_core::ModuleSpecifier;
+use deno_core::SourceCodeCacheInfo;
+use sha2::{Digest, Sha256};
+
+use crate::meter;
+
+/// Prefix of the standard library modules, e.g. `comet:store`.
+const STD_SCHEME: &str = "comet:";
+/// Standard library modules, embedded in the startup snapshot by `build.rs`.
+const STD_MODULES: &[&str] = &["store", "context", "crypto", "encoding"];
+/// Versions of the standard library. Scripts pick one with e.g.
+/// `comet:store@1`, and get the first one if they do not, so that existing
+/// scripts keep working as the library evolves.
+const STD_VERSIONS: &[u32] = &[1];
+
+/// Specifier of the module embedded in the snapshot that `specifier`, e.g.
+/// `comet:store@1`, stands for.
+fn resolve_std(specifier: &str) -> Result<ModuleSpecifier, AnyError> {
+    let name = &specifier[STD_SCHEME.len()..];
+    let (name, version) = match name.split_once('@') {
+        Some((name, version)) => (name, version.parse().unwrap_or(0)),
+        None => (name, STD_VERSIONS[0]),
+    };
+    if !STD_MODULES.contains(&name) || !STD_VERSIONS.contains(&version) {
+        bail!("unknown standard module {}", specifier);
+    }
+
+    Ok(ModuleSpecifier::parse(&format!(
+        "ext:kvstore_app/v{}/{}.js",
+        version, name
+    ))?)
+}
+
+/// Instrumented and transpiled modules and their V8 code cache, shared by
+/// every runtime of a thread so a script is only parsed the first time it
+/// runs.
+///
+/// Entries are keyed by specifier and hold the hash of the source they were
+/// built from, so an edited script is transpiled again. Transpiled output is
+/// also written to `dir`, if any, to survive restarts.
+#[derive(Debug, Default)]
+pub struct ModuleCache {
+    dir: Option<PathBuf>,
+    entries: RefCell<HashMap<ModuleSpecifier, CachedModule>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedModule {
+    source_hash: [u8; 32],
+    code: String,
+    code_cache: Option<Vec<u8>>,
+}
+
+impl ModuleCache {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            entries: Default::default(),
+        }
+    }
+
+    fn get(&self, specifier: &ModuleSpecifier, source_hash: &[u8; 32]) -> Option<CachedModule> {
+        self.entries
+            .borrow()
+            .get(specifier)
+            .filter(|entry| &entry.source_hash == source_hash)
+            .cloned()
+    }
+
+    fn insert(&self, specifier: ModuleSpecifier, source_hash: [u8; 32], code: String) {
+        self.entries.borrow_mut().insert(
+            specifier,
+            CachedModule {
+                source_hash,
+                code,
+                code_cache: None,
+            },
+        );
+    }
+
+    fn set_code_cache(&self, specifier: &ModuleSpecifier, hash: u64, code_cache: &[u8]) {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(specifier) {
+            if code_cache_hash(&entry.source_hash) == hash {
+                entry.code_cache = Some(code_cache.to_vec());
+            }
+        }
+    }
+
+    /// On-disk location of the transpiled output of `specifier` at `source_hash`.
+    fn disk_path(&self, specifier: &ModuleSpecifier, source_hash: &[u8; 32]) -> Option<PathBuf> {
+        let digest = Sha256::new()
+            .chain_update(CACHE_VERSION)
+            .chain_update(specifier.as_str())
+            .chain_update(source_hash)
+            .finalize();
+        let name = digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        Some(self.dir.as_ref()?.join(format!("{}.js", name)))
+    }
 
-pub struct TsModuleLoader;
+    /// Returns the code to evaluate for `source`, instrumenting it, see
+    /// `meter`, and transpiling it if needed, only if neither the memory nor
+    /// the disk cache has it.
+    fn load(
+        &self,
+        specifier: &ModuleSpecifier,
+        media_type: MediaType,
+        source: String,
+        should_transpile: bool,
+    ) -> Result<CachedModule, AnyError> {
+        let source_hash: [u8; 32] = Sha256::digest(&source).into();
+        if let Some(entry) = self.get(specifier, &source_hash) {
+            return Ok(entry);
+        }
+        if media_type == MediaType::Json {
+            self.insert(specifier.clone(), source_hash, source);
+            return Ok(self.get(specifier, &source_hash).expect("just inserted"));
+        }
+
+        let disk_path = self.disk_path(specifier, &source_hash);
+        let code = match disk_path
+            .as_ref()
+            .and_then(|p| std::fs::read_to_string(p).ok())
+        {
+            Some(code) => code,
+            None => {
+                let mut code = meter::instrument(specifier, media_type, source)?;
+                if should_transpile {
+                    code = transpile(specifier, media_type, code)?;
+                }
+                if let Some(path) = disk_path {
+                    if let Err(err) = write_atomic(&path, &code) {
+                        tracing::warn!("failed to cache {}: {}", specifier, err);
+                    }
+                }
+                code
+            }
+        };
+
+        self.insert(specifier.clone(), source_hash, code);
+        Ok(self.get(specifier, &source_hash).expect("just inserted"))
+    }
+}
+
+/// Version of the output of `ModuleCache::load`, part of the key of the disk
+/// cache so a node does not reuse what an older version wrote.
+const CACHE_VERSION: &[u8] = b"2";
+
+/// Hash V8 checks a code cache against, derived from the source hash.
+fn code_cache_hash(source_hash: &[u8; 32]) -> u64 {
+    u64::from_le_bytes(source_hash[..8].try_into().expect("8 bytes"))
+}
+
+fn transpile(
+    specifier: &ModuleSpecifier,
+    media_type: MediaType,
+    code: String,
+) -> Result<String, AnyError> {
+    let parsed = deno_ast::parse_module(ParseParams {
+        specifier: specifier.clone(),
+        media_type,
+        capture_tokens: false,
+        scope_analysis: false,
+        maybe_syntax: None,
+        text: SourceTextInfo::from_string(code).text(),
+    })?;
+    Ok(parsed
+        .transpile(&Default::default(), &Default::default())?
+        .into_source()
+        .into_string()?
+        .text)
+}
+
+fn write_atomic(path: &Path, code: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, code)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Loads scripts and the modules they import, which must all be files under
+/// the scripts directory, e.g. shared helpers in `scripts/lib/`, or modules of
+/// the `comet:` standard library.
+pub struct TsModuleLoader {
+    scripts_dir: PathBuf,
+    cache: Rc<ModuleCache>,
+}
+
+impl TsModuleLoader {
+    pub fn new(scripts_dir: PathBuf, cache: Rc<ModuleCache>) -> Self {
+        Self { scripts_dir, cache }
+    }
+
+    /// Path of the module at `specifier`, failing unless it is a file under
+    /// the scripts directory once symlinks and `..` are resolved.
+    fn sandboxed_path(&self, specifier: &ModuleSpecifier) -> Result<PathBuf, AnyError> {
+        if specifier.scheme() != "file" {
+            bail!("cannot import {}: only file imports are allowed", specifier);
+        }
+        let path = specifier
+            .to_file_path()
+            .map_err(|_| anyhow!("cannot import {}: invalid file URL", specifier))?;
+        let path = std::fs::canonicalize(path)
+            .map_err(|err| anyhow!("cannot import {}: {}", specifier, err))?;
+        let scripts_dir = std::fs::canonicalize(&self.scripts_dir)?;
+        if !path.starts_with(scripts_dir) {
+            bail!(
+                "cannot import {}: outside of the scripts directory",
+                specifier
+            );
+        }
+
+        Ok(path)
+    }
+}
 
 impl deno_core::ModuleLoader for TsModuleLoader {
     fn resolve(
@@ -14,7 +240,13 @@ impl deno_core::ModuleLoader for TsModuleLoader {
         referrer: &str,
         _kind: deno_core::ResolutionKind,
     ) -> Result<deno_core::ModuleSpecifier, AnyError> {
-        deno_core::resolve_import(specifier, referrer).map_err(|e| e.into())
+        if specifier.starts_with(STD_SCHEME) {
+            return resolve_std(specifier);
+        }
+
+        let specifier = deno_core::resolve_import(specifier, referrer)?;
+        self.sandboxed_path(&specifier)?;
+        Ok(specifier)
     }
 
     fn load(
@@ -25,10 +257,13 @@ impl deno_core::ModuleLoader for TsModuleLoader {
         _requested_module_type: deno_core::RequestedModuleType,
     ) -> ModuleLoadResponse {
         let module_specifier = module_specifier.clone();
+        let cache = Rc::clone(&self.cache);
+        let path = match self.sandboxed_path(&module_specifier) {
+            Ok(path) => path,
+            Err(err) => return ModuleLoadResponse::Sync(Err(err)),
+        };
 
         let module_load = Box::pin(async move {
-            let path = module_specifier.to_file_path().unwrap();
-
             let media_type = MediaType::from_path(&path);
             let (module_type, should_transpile) = match MediaType::from_path(&path) {
                 MediaType::JavaScript | MediaType::Mjs | MediaType::Cjs => {
@@ -43,37 +278,37 @@ impl deno_core::ModuleLoader for TsModuleLoader {
                 | MediaType::Dcts
                 | MediaType::Tsx => (deno_core::ModuleType::JavaScript, true),
                 MediaType::Json => (deno_core::ModuleType::Json, false),
-                _ => panic!("Unknown extension {:?}", path.extension()),
+                _ => bail!("cannot import {}: unknown extension", module_specifier),
             };
 
             let code = std::fs::read_to_string(&path)?;
-            let code = if should_transpile {
-                let parsed = deno_ast::parse_module(ParseParams {
-                    specifier: module_specifier.clone(),
-                    media_type,
-                    capture_tokens: false,
-                    scope_analysis: false,
-                    maybe_syntax: None,
-                    text: SourceTextInfo::from_string(code).text(),
-                })?;
-                parsed
-                    .transpile(&Default::default(), &Default::default())?
-                    .into_source()
-                    .into_string()
-                    .unwrap()
-                    .text
-            } else {
-                code
-            };
+            let entry = cache.load(&module_specifier, media_type, code, should_transpile)?;
+            let code_cache = matches!(module_type, deno_core::ModuleType::JavaScript).then(|| {
+                SourceCodeCacheInfo {
+                    hash: code_cache_hash(&entry.source_hash),
+                    data: entry.code_cache.map(Cow::Owned),
+                }
+            });
             let module = deno_core::ModuleSource::new(
                 module_type,
-                ModuleSourceCode::String(code.into()),
+                ModuleSourceCode::String(entry.code.into()),
                 &module_specifier,
-                None,
+                code_cache,
             );
             Ok(module)
         });
 
         ModuleLoadResponse::Async(module_load)
     }
+
+    fn code_cache_ready(
+        &self,
+        module_specifier: ModuleSpecifier,
+        hash: u64,
+        code_cache: &[u8],
+    ) -> Pin<Box<dyn Future<Output = ()>>> {
+        self.cache
+            .set_code_cache(&module_specifier, hash, code_cache);
+        Box::pin(async {})
+    }
 }
@@ -1,18 +1,102 @@
+mod gas;
+mod genesis;
+mod lint;
 mod loader;
+mod merkle;
+mod meter;
+mod proof;
+mod pruning;
 mod runner;
 mod runtime;
 mod runtime_ops;
 mod script;
 mod service;
+mod snapshot;
 mod store;
+#[cfg(test)]
+mod test_util;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use bytes::Bytes;
+use lint::LintOptions;
+use pruning::Pruning;
 use runner::Runner;
+use runtime::{Limits, PoolOptions};
 use serde_json::json;
 use service::DenoKVService;
+use snapshot::Snapshots;
+use store::{DiskStore, MemoryStore, Store};
+use structopt::StructOpt;
 use tendermint::abci::Event;
 use tendermint_abci::{ClientBuilder, Server, ServerBuilder};
 use tendermint_proto::abci::{RequestEcho, RequestFinalizeBlock, RequestQuery};
+use tokio::sync::Mutex;
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// Directory where the application state is persisted.
+    #[structopt(long, default_value = "./data")]
+    data_dir: PathBuf,
+
+    /// Keep the application state in memory instead of in `--data-dir`,
+    /// losing it when the node stops.
+    #[structopt(long)]
+    memory_store: bool,
+
+    /// Which historical heights to keep: `nothing` prunes nothing,
+    /// `everything` keeps only the latest height and `custom` keeps the ones
+    /// selected by `--pruning-keep-recent` and `--pruning-keep-every`.
+    #[structopt(long, default_value = "nothing")]
+    pruning: String,
+
+    /// Number of latest heights kept by the `custom` pruning strategy.
+    #[structopt(long, default_value = "100")]
+    pruning_keep_recent: i64,
+
+    /// Interval at which the `custom` pruning strategy keeps older heights, 0
+    /// to keep none of them.
+    #[structopt(long, default_value = "0")]
+    pruning_keep_every: i64,
+
+    /// Directory where state sync snapshots are written.
+    #[structopt(long, default_value = "./snapshots")]
+    snapshot_dir: PathBuf,
+
+    /// Take a snapshot at every height that is a multiple of this interval, 0
+    /// to never take one.
+    #[structopt(long, default_value = "0")]
+    snapshot_interval: i64,
+
+    /// Number of snapshots to keep, 0 to keep all of them.
+    #[structopt(long, default_value = "2")]
+    snapshot_keep_recent: usize,
+
+    /// Milliseconds a script may run before the node halts. Gas bounds the
+    /// work of a script, so this is only a last-resort guard and should sit
+    /// far above what any script within its gas limit needs.
+    #[structopt(long, default_value = "60000")]
+    script_timeout_ms: u64,
+
+    /// Megabytes the V8 heap of a script may grow to before it is terminated.
+    #[structopt(long, default_value = "64")]
+    script_max_heap_mb: usize,
+
+    /// Also refuse scripts calling `Math` functions whose results may differ
+    /// across platforms.
+    #[structopt(long)]
+    lint_float: bool,
+
+    /// Number of script runtimes created ahead of time, while the node is idle.
+    #[structopt(long, default_value = "4")]
+    runtime_pool_size: usize,
+
+    /// Directory transpiled scripts are cached in across restarts.
+    #[structopt(long)]
+    script_cache_dir: Option<PathBuf>,
+}
+
+const SCRIPTS_DIR: &str = "./scripts";
 
 #[tokio::main]
 async fn start_server(server: Server<DenoKVService>) -> anyhow::Result<()> {
@@ -23,23 +107,63 @@ async fn start_server(server: Server<DenoKVService>) -> anyhow::Result<()> {
     Ok(server.listen()?)
 }
 
+/// Runs `runner` until it stops, halting the whole node if it fails, as the
+/// server cannot answer consensus without it.
 #[tokio::main]
 async fn start_runner(mut runner: Runner) -> anyhow::Result<()> {
     let res = runner.run().await;
     match res {
         Ok(_) => tracing::info!("runner finished successfully"),
-        Err(e) => tracing::error!("runner error: {:?}", e),
+        Err(e) => {
+            tracing::error!("runner error, halting: {:?}", e);
+            std::process::exit(1);
+        }
     }
 
     Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    let (app, runner) = DenoKVService::new("./scripts");
+    let pruning = Pruning::new(
+        &opt.pruning,
+        opt.pruning_keep_recent,
+        opt.pruning_keep_every,
+    )?;
+    let snapshots = Snapshots::new(
+        &opt.snapshot_dir,
+        SCRIPTS_DIR,
+        opt.snapshot_interval,
+        opt.snapshot_keep_recent,
+    );
+    let store: Arc<Mutex<dyn Store>> = if opt.memory_store {
+        Arc::new(Mutex::new(MemoryStore::new()))
+    } else {
+        Arc::new(Mutex::new(DiskStore::open(&opt.data_dir)?))
+    };
+    let (app, runner) = DenoKVService::new(
+        SCRIPTS_DIR,
+        store,
+        pruning,
+        snapshots,
+        Limits {
+            timeout: Duration::from_millis(opt.script_timeout_ms),
+            max_heap_bytes: opt.script_max_heap_mb << 20,
+        },
+        LintOptions {
+            float: opt.lint_float,
+        },
+        PoolOptions {
+            scripts_dir: SCRIPTS_DIR.into(),
+            size: opt.runtime_pool_size,
+            cache_dir: opt.script_cache_dir,
+        },
+    )?;
 
     let server = ServerBuilder::default().bind("127.0.0.1:26658", app)?;
     let server_url = server.local_addr();
@@ -84,6 +208,9 @@ fn main() -> anyhow::Result<()> {
         Event::new("kv-set", [("name", "eddy")]).into()
     );
 
+    // COMMIT
+    client.commit()?;
+
     // GET
     let res = client.query(RequestQuery {
         path: "kv-get".to_string(),
@@ -0,0 +1,666 @@
+//! Versioned sparse Merkle tree over the key/value pairs of a store.
+//!
+//! Every pair is a leaf at the path given by the bits of the SHA-256 of its
+//! key, placed at the shallowest depth where no other key shares its path, so
+//! the shape of the tree only depends on the pairs it holds. Leaves and inner
+//! nodes are domain separated by a one byte prefix, and an empty subtree
+//! hashes to all zeroes.
+//!
+//! Nodes are never modified: a commit writes new nodes along the paths of the
+//! keys it changed, tagged with its height, and reports the nodes they replace
+//! as stale so pruning can delete them once no kept height sees them anymore.
+
+use std::collections::BTreeMap;
+
+use deno_core::error::AnyError;
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// Hash of an empty subtree, and so the root of an empty tree.
+pub const EMPTY: Hash = [0; 32];
+
+const LEAF_PREFIX: u8 = 0;
+const INNER_PREFIX: u8 = 1;
+
+pub fn key_hash(key: &[u8]) -> Hash {
+    Sha256::digest(key).into()
+}
+
+pub fn value_hash(value: &[u8]) -> Hash {
+    Sha256::digest(value).into()
+}
+
+pub fn leaf_hash(key_hash: &Hash, value_hash: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(key_hash);
+    hasher.update(value_hash);
+    hasher.finalize().into()
+}
+
+pub fn inner_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([INNER_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Bit of `hash` at `depth`, 0 going left and 1 going right.
+fn bit(hash: &Hash, depth: usize) -> usize {
+    ((hash[depth / 8] >> (7 - depth % 8)) & 1) as usize
+}
+
+/// First `depth` bits of `hash`, followed by zeroes.
+fn prefix(hash: &Hash, depth: usize) -> Hash {
+    let mut prefix = [0; 32];
+    for (i, byte) in prefix.iter_mut().enumerate() {
+        let bits = depth.saturating_sub(i * 8).min(8);
+        *byte = hash[i] & !(0xffu8.checked_shr(bits as u32).unwrap_or(0));
+    }
+    prefix
+}
+
+/// `prefix` extended with `bit` at `depth`.
+fn child_path(prefix: &Hash, depth: usize, bit: usize) -> Hash {
+    let mut path = *prefix;
+    path[depth / 8] |= (bit as u8) << (7 - depth % 8);
+    path
+}
+
+/// Where a node is stored: the height that wrote it and its position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NodeKey {
+    pub version: i64,
+    pub depth: usize,
+    /// First `depth` bits of the key hashes below the node, then zeroes.
+    pub path: Hash,
+}
+
+impl NodeKey {
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            &self.version.to_be_bytes()[..],
+            &(self.depth as u16).to_be_bytes(),
+            &self.path,
+        ]
+        .concat()
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self, AnyError> {
+        if raw.len() != 42 {
+            return Err(AnyError::msg("corrupted node key"));
+        }
+        Ok(Self {
+            version: i64::from_be_bytes(raw[..8].try_into()?),
+            depth: u16::from_be_bytes(raw[8..10].try_into()?) as usize,
+            path: raw[10..].try_into()?,
+        })
+    }
+}
+
+/// Reference from a parent, or a height, to the node below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Child {
+    pub hash: Hash,
+    /// Height that wrote the node.
+    pub version: i64,
+}
+
+impl Child {
+    fn encode_into(child: &Option<Child>, out: &mut Vec<u8>) {
+        match child {
+            Some(child) => {
+                out.push(1);
+                out.extend_from_slice(&child.hash);
+                out.extend_from_slice(&child.version.to_be_bytes());
+            }
+            None => out.push(0),
+        }
+    }
+
+    fn decode_from(raw: &[u8]) -> Result<(Option<Child>, &[u8]), AnyError> {
+        match raw.split_first() {
+            Some((0, rest)) => Ok((None, rest)),
+            Some((1, rest)) if rest.len() >= 40 => Ok((
+                Some(Child {
+                    hash: rest[..32].try_into()?,
+                    version: i64::from_be_bytes(rest[32..40].try_into()?),
+                }),
+                &rest[40..],
+            )),
+            _ => Err(AnyError::msg("corrupted tree node")),
+        }
+    }
+
+    /// Encoding of the root of a tree, `None` if it is empty.
+    pub fn encode_root(root: &Option<Child>) -> Vec<u8> {
+        let mut out = vec![];
+        Self::encode_into(root, &mut out);
+        out
+    }
+
+    pub fn decode_root(raw: &[u8]) -> Result<Option<Child>, AnyError> {
+        Ok(Self::decode_from(raw)?.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Leaf { key_hash: Hash, value_hash: Hash },
+    Inner { children: [Option<Child>; 2] },
+}
+
+impl Node {
+    pub fn hash(&self) -> Hash {
+        match self {
+            Node::Leaf {
+                key_hash,
+                value_hash,
+            } => leaf_hash(key_hash, value_hash),
+            Node::Inner { children } => {
+                let hash = |child: &Option<Child>| child.map_or(EMPTY, |c| c.hash);
+                inner_hash(&hash(&children[0]), &hash(&children[1]))
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Node::Leaf {
+                key_hash,
+                value_hash,
+            } => [&[LEAF_PREFIX][..], key_hash, value_hash].concat(),
+            Node::Inner { children } => {
+                let mut out = vec![INNER_PREFIX];
+                for child in children {
+                    Child::encode_into(child, &mut out);
+                }
+                out
+            }
+        }
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self, AnyError> {
+        match raw.split_first() {
+            Some((&LEAF_PREFIX, rest)) if rest.len() == 64 => Ok(Node::Leaf {
+                key_hash: rest[..32].try_into()?,
+                value_hash: rest[32..].try_into()?,
+            }),
+            Some((&INNER_PREFIX, rest)) => {
+                let (left, rest) = Child::decode_from(rest)?;
+                let (right, _) = Child::decode_from(rest)?;
+                Ok(Node::Inner {
+                    children: [left, right],
+                })
+            }
+            _ => Err(AnyError::msg("corrupted tree node")),
+        }
+    }
+}
+
+/// Storage the nodes of a tree are read from.
+pub trait TreeReader {
+    fn node(&self, key: &NodeKey) -> Result<Option<Node>, AnyError>;
+}
+
+impl TreeReader for BTreeMap<NodeKey, Node> {
+    fn node(&self, key: &NodeKey) -> Result<Option<Node>, AnyError> {
+        Ok(self.get(key).cloned())
+    }
+}
+
+/// Nodes to write and delete to go from one version of a tree to the next.
+#[derive(Debug, Default)]
+pub struct TreeUpdate {
+    pub root: Option<Child>,
+    pub nodes: BTreeMap<NodeKey, Node>,
+    /// Nodes of the previous version that the new one no longer uses.
+    pub stale: Vec<NodeKey>,
+}
+
+impl TreeUpdate {
+    pub fn root_hash(&self) -> Hash {
+        self.root.map_or(EMPTY, |root| root.hash)
+    }
+}
+
+/// Applies `changes`, the new value of every changed key or `None` for a
+/// deleted one, to the tree at `root`, writing new nodes as of `version`.
+///
+/// Only the nodes on the paths of the changed keys are read and replaced.
+pub fn update(
+    reader: &dyn TreeReader,
+    root: Option<Child>,
+    version: i64,
+    changes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+) -> Result<TreeUpdate, AnyError> {
+    let mut entries = changes
+        .iter()
+        .map(|(key, value)| Entry {
+            key_hash: key_hash(key),
+            value_hash: value.as_deref().map(value_hash),
+            stored: None,
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.key_hash);
+
+    let mut updater = Updater {
+        reader,
+        version,
+        update: TreeUpdate::default(),
+    };
+    let subtree = updater.apply(0, EMPTY, root, entries)?;
+    updater.update.root = updater.place(0, EMPTY, subtree)?;
+    Ok(updater.update)
+}
+
+/// Root hash of the tree holding exactly `pairs`.
+pub fn root_of(pairs: &[(Vec<u8>, Vec<u8>)]) -> Result<Hash, AnyError> {
+    let changes = pairs
+        .iter()
+        .map(|(key, value)| (key.clone(), Some(value.clone())))
+        .collect();
+    Ok(update(&BTreeMap::<NodeKey, Node>::new(), None, 0, &changes)?.root_hash())
+}
+
+/// Leaf to place in the tree being updated.
+struct Entry {
+    key_hash: Hash,
+    /// `None` for a key to delete.
+    value_hash: Option<Hash>,
+    /// Where the leaf is stored if it was already in the tree.
+    stored: Option<NodeKey>,
+}
+
+/// Subtree of the tree being updated.
+enum Subtree {
+    Empty,
+    /// A single leaf, which moves up until it has a sibling.
+    Leaf(Entry),
+    /// A node stored at the position of the subtree.
+    Node(Child),
+}
+
+struct Updater<'a> {
+    reader: &'a dyn TreeReader,
+    version: i64,
+    update: TreeUpdate,
+}
+
+impl Updater<'_> {
+    fn node(&self, key: &NodeKey) -> Result<Node, AnyError> {
+        match self.update.nodes.get(key) {
+            Some(node) => Ok(node.clone()),
+            None => self
+                .reader
+                .node(key)?
+                .ok_or_else(|| AnyError::msg(format!("missing tree node {:?}", key))),
+        }
+    }
+
+    /// Applies `entries`, which all start with the `depth` bits of `path`, to
+    /// the subtree at `child`.
+    fn apply(
+        &mut self,
+        depth: usize,
+        path: Hash,
+        child: Option<Child>,
+        mut entries: Vec<Entry>,
+    ) -> Result<Subtree, AnyError> {
+        let Some(child) = child else {
+            return self.build(depth, path, entries);
+        };
+        if entries.is_empty() {
+            return Ok(Subtree::Node(child));
+        }
+
+        let key = NodeKey {
+            version: child.version,
+            depth,
+            path,
+        };
+        match self.node(&key)? {
+            Node::Leaf {
+                key_hash,
+                value_hash,
+            } => {
+                // The leaf is either replaced by an entry or placed again
+                // along with them.
+                match entries.binary_search_by_key(&key_hash, |entry| entry.key_hash) {
+                    Ok(_) => self.update.stale.push(key),
+                    Err(index) => entries.insert(
+                        index,
+                        Entry {
+                            key_hash,
+                            value_hash: Some(value_hash),
+                            stored: Some(key),
+                        },
+                    ),
+                }
+                self.build(depth, path, entries)
+            }
+            Node::Inner { children: [l, r] } => {
+                self.update.stale.push(key);
+                let split = entries.partition_point(|entry| bit(&entry.key_hash, depth) == 0);
+                let right_entries = entries.split_off(split);
+                let left = self.apply(depth + 1, child_path(&path, depth, 0), l, entries)?;
+                let right = self.apply(depth + 1, child_path(&path, depth, 1), r, right_entries)?;
+                self.join(depth, path, left, right)
+            }
+        }
+    }
+
+    /// Subtree of `entries` where there is no node yet.
+    fn build(
+        &mut self,
+        depth: usize,
+        path: Hash,
+        mut entries: Vec<Entry>,
+    ) -> Result<Subtree, AnyError> {
+        entries.retain(|entry| entry.value_hash.is_some());
+        match entries.len() {
+            0 => Ok(Subtree::Empty),
+            1 => Ok(Subtree::Leaf(entries.pop().expect("one entry"))),
+            _ => {
+                let split = entries.partition_point(|entry| bit(&entry.key_hash, depth) == 0);
+                let right_entries = entries.split_off(split);
+                let left = self.build(depth + 1, child_path(&path, depth, 0), entries)?;
+                let right = self.build(depth + 1, child_path(&path, depth, 1), right_entries)?;
+                self.join(depth, path, left, right)
+            }
+        }
+    }
+
+    /// Subtree at `depth` with the given children.
+    fn join(
+        &mut self,
+        depth: usize,
+        path: Hash,
+        left: Subtree,
+        right: Subtree,
+    ) -> Result<Subtree, AnyError> {
+        // A lone leaf moves up to where it has a sibling, so it has to be
+        // told apart from a lone inner node.
+        let lone = |updater: &Self, bit: usize, child: &Child| -> Result<Option<Entry>, AnyError> {
+            let key = NodeKey {
+                version: child.version,
+                depth: depth + 1,
+                path: child_path(&path, depth, bit),
+            };
+            Ok(match updater.node(&key)? {
+                Node::Leaf {
+                    key_hash,
+                    value_hash,
+                } => Some(Entry {
+                    key_hash,
+                    value_hash: Some(value_hash),
+                    stored: Some(key),
+                }),
+                Node::Inner { .. } => None,
+            })
+        };
+        match (left, right) {
+            (Subtree::Empty, Subtree::Empty) => Ok(Subtree::Empty),
+            (Subtree::Leaf(entry), Subtree::Empty) | (Subtree::Empty, Subtree::Leaf(entry)) => {
+                Ok(Subtree::Leaf(entry))
+            }
+            (Subtree::Node(child), Subtree::Empty) => match lone(self, 0, &child)? {
+                Some(entry) => Ok(Subtree::Leaf(entry)),
+                None => self.inner(depth, path, Subtree::Node(child), Subtree::Empty),
+            },
+            (Subtree::Empty, Subtree::Node(child)) => match lone(self, 1, &child)? {
+                Some(entry) => Ok(Subtree::Leaf(entry)),
+                None => self.inner(depth, path, Subtree::Empty, Subtree::Node(child)),
+            },
+            (left, right) => self.inner(depth, path, left, right),
+        }
+    }
+
+    /// Writes the inner node at `depth` with the given children.
+    fn inner(
+        &mut self,
+        depth: usize,
+        path: Hash,
+        left: Subtree,
+        right: Subtree,
+    ) -> Result<Subtree, AnyError> {
+        let node = Node::Inner {
+            children: [
+                self.place(depth + 1, child_path(&path, depth, 0), left)?,
+                self.place(depth + 1, child_path(&path, depth, 1), right)?,
+            ],
+        };
+        let child = Child {
+            hash: node.hash(),
+            version: self.version,
+        };
+        let key = NodeKey {
+            version: self.version,
+            depth,
+            path,
+        };
+        self.update.nodes.insert(key, node);
+        Ok(Subtree::Node(child))
+    }
+
+    /// Stores `subtree` at `depth`, unless it is already stored there.
+    fn place(
+        &mut self,
+        depth: usize,
+        path: Hash,
+        subtree: Subtree,
+    ) -> Result<Option<Child>, AnyError> {
+        match subtree {
+            Subtree::Empty => Ok(None),
+            Subtree::Node(child) => Ok(Some(child)),
+            Subtree::Leaf(entry) => {
+                let value_hash = entry.value_hash.expect("built leaves have a value");
+                let hash = leaf_hash(&entry.key_hash, &value_hash);
+                match entry.stored {
+                    Some(stored) if stored.depth == depth => {
+                        return Ok(Some(Child {
+                            hash,
+                            version: stored.version,
+                        }))
+                    }
+                    Some(stored) => self.update.stale.push(stored),
+                    None => {}
+                }
+
+                let key = NodeKey {
+                    version: self.version,
+                    depth,
+                    path,
+                };
+                self.update.nodes.insert(
+                    key,
+                    Node::Leaf {
+                        key_hash: entry.key_hash,
+                        value_hash,
+                    },
+                );
+                Ok(Some(Child {
+                    hash,
+                    version: self.version,
+                }))
+            }
+        }
+    }
+}
+
+/// Path from the root of a tree towards the leaf of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    /// Hashes of the siblings of the nodes on the path, from the root down.
+    pub siblings: Vec<Hash>,
+    /// Leaf the path ends at, as its key hash and value hash, or `None` if it
+    /// ends at an empty subtree.
+    pub leaf: Option<(Hash, Hash)>,
+}
+
+/// Path of `key` in the tree at `root`. It ends at the leaf of the key if it
+/// is in the tree, and at another leaf or an empty subtree otherwise.
+pub fn prove(reader: &dyn TreeReader, root: Option<Child>, key: &[u8]) -> Result<Proof, AnyError> {
+    let key_hash = key_hash(key);
+    let mut proof = Proof {
+        siblings: vec![],
+        leaf: None,
+    };
+    let mut child = root;
+    let mut depth = 0;
+    while let Some(current) = child {
+        let node_key = NodeKey {
+            version: current.version,
+            depth,
+            path: prefix(&key_hash, depth),
+        };
+        let node = reader
+            .node(&node_key)?
+            .ok_or_else(|| AnyError::msg(format!("missing tree node {:?}", node_key)))?;
+        match node {
+            Node::Leaf {
+                key_hash,
+                value_hash,
+            } => {
+                proof.leaf = Some((key_hash, value_hash));
+                break;
+            }
+            Node::Inner { children } => {
+                let bit = bit(&key_hash, depth);
+                proof
+                    .siblings
+                    .push(children[1 - bit].map_or(EMPTY, |c| c.hash));
+                child = children[bit];
+                depth += 1;
+            }
+        }
+    }
+    Ok(proof)
+}
+
+/// Checks that `proof` shows `key` to hold `value`, or to be absent if
+/// `None`, in the tree whose root hash is `root`.
+pub fn verify(root: &Hash, key: &[u8], value: Option<&[u8]>, proof: &Proof) -> bool {
+    let key_hash = key_hash(key);
+    let depth = proof.siblings.len();
+    if depth > 256 {
+        return false;
+    }
+    let bottom = match (value, proof.leaf) {
+        (Some(value), Some((leaf_key, leaf_value))) => {
+            if leaf_key != key_hash || leaf_value != value_hash(value) {
+                return false;
+            }
+            leaf_hash(&leaf_key, &leaf_value)
+        }
+        // Another key occupying the path rules out this one, as a leaf sits
+        // at the shallowest depth where no other key shares its path.
+        (None, Some((leaf_key, leaf_value))) => {
+            if leaf_key == key_hash || prefix(&leaf_key, depth) != prefix(&key_hash, depth) {
+                return false;
+            }
+            leaf_hash(&leaf_key, &leaf_value)
+        }
+        (None, None) => EMPTY,
+        (Some(_), None) => return false,
+    };
+
+    let computed =
+        proof
+            .siblings
+            .iter()
+            .enumerate()
+            .rev()
+            .fold(bottom, |hash, (depth, sibling)| {
+                match bit(&key_hash, depth) {
+                    0 => inner_hash(&hash, sibling),
+                    _ => inner_hash(sibling, &hash),
+                }
+            });
+    &computed == root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn changes(items: &[(&str, Option<&str>)]) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+        items
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.map(|v| v.as_bytes().to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn test_update() {
+        let mut nodes = BTreeMap::new();
+        let mut root = None;
+        let mut apply = |version, items: &[(&str, Option<&str>)]| {
+            let update = update(&nodes, root, version, &changes(items)).unwrap();
+            for key in &update.stale {
+                assert!(nodes.remove(key).is_some());
+            }
+            nodes.extend(update.nodes);
+            root = update.root;
+            (update.root_hash(), nodes.len())
+        };
+
+        let (one, _) = apply(1, &[("a", Some("1")), ("b", Some("2")), ("c", Some("3"))]);
+        let (two, _) = apply(2, &[("b", Some("4")), ("d", Some("5"))]);
+        let (three, len) = apply(3, &[("b", None), ("d", None), ("e", None)]);
+
+        // The root only depends on the pairs, not on how they got there.
+        let pairs = |items: &[(&str, &str)]| {
+            items
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            one,
+            root_of(&pairs(&[("a", "1"), ("b", "2"), ("c", "3")])).unwrap()
+        );
+        assert_eq!(
+            two,
+            root_of(&pairs(&[("a", "1"), ("b", "4"), ("c", "3"), ("d", "5")])).unwrap()
+        );
+        assert_eq!(three, root_of(&pairs(&[("a", "1"), ("c", "3")])).unwrap());
+        assert_ne!(one, three);
+        // Every stale node was dropped, leaving the root and two leaves, as
+        // the key hashes of `a` and `c` differ in their first bit.
+        assert_eq!(len, 3);
+
+        let (empty, len) = apply(4, &[("a", None), ("c", None)]);
+        assert_eq!((empty, len), (EMPTY, 0));
+    }
+
+    #[test]
+    fn test_prove_and_verify() {
+        let pairs = (0..20)
+            .map(|i| (format!("key{}", i).into_bytes(), i.to_string().into_bytes()))
+            .collect::<Vec<_>>();
+        let changes = pairs
+            .iter()
+            .map(|(k, v)| (k.clone(), Some(v.clone())))
+            .collect();
+        let update = update(&BTreeMap::<NodeKey, Node>::new(), None, 1, &changes).unwrap();
+        let root = update.root_hash();
+
+        for (key, value) in &pairs {
+            let proof = prove(&update.nodes, update.root, key).unwrap();
+            assert!(verify(&root, key, Some(value), &proof));
+            assert!(!verify(&root, key, Some(b"forged"), &proof));
+            assert!(!verify(&root, key, None, &proof));
+        }
+        for key in ["absent", "key20", ""] {
+            let proof = prove(&update.nodes, update.root, key.as_bytes()).unwrap();
+            assert!(verify(&root, key.as_bytes(), None, &proof));
+            assert!(!verify(&root, key.as_bytes(), Some(b"0"), &proof));
+        }
+
+        let proof = prove(&BTreeMap::<NodeKey, Node>::new(), None, b"a").unwrap();
+        assert!(verify(&EMPTY, b"a", None, &proof));
+    }
+}
@@ -0,0 +1,230 @@
+//! Deterministic execution budget of scripts.
+//!
+//! Every module a script loads is rewritten to call `__comet_tick` at the
+//! start of every loop iteration and function body, and `runtime.js` charges
+//! those ticks to the run's gas. A script thus runs out of gas after the same
+//! amount of work on every node, however fast each one runs it.
+
+use anyhow::bail;
+use deno_ast::{
+    swc::{
+        ast::{
+            ArrowExpr, BlockStmt, BlockStmtOrExpr, Constructor, DoWhileStmt, ForInStmt, ForOfStmt,
+            ForStmt, Function, GetterProp, Ident, SetterProp, Stmt, WhileStmt,
+        },
+        visit::{Visit, VisitWith},
+    },
+    MediaType, ParseParams, SourcePos, SourceRangedForSpanned, SourceTextInfo, StartSourcePos,
+};
+use deno_core::{error::AnyError, ModuleSpecifier};
+
+/// Function the injected code calls, defined by `runtime.js`.
+const TICK_FUNCTION: &str = "__comet_tick";
+const TICK: &str = "__comet_tick();";
+
+/// Returns `source` with a tick at the start of every loop body and function
+/// body. Only text is inserted, and never a line break, so positions in stack
+/// traces keep their line.
+///
+/// Fails if `source` names `__comet_tick` itself, which could shadow it.
+pub fn instrument(
+    specifier: &ModuleSpecifier,
+    media_type: MediaType,
+    source: String,
+) -> Result<String, AnyError> {
+    let parsed = deno_ast::parse_module(ParseParams {
+        specifier: specifier.clone(),
+        media_type,
+        capture_tokens: false,
+        scope_analysis: false,
+        maybe_syntax: None,
+        text: SourceTextInfo::from_string(source).text(),
+    })?;
+
+    let mut visitor = Instrumenter {
+        inserts: vec![],
+        reserved: None,
+    };
+    parsed.module().visit_with(&mut visitor);
+    if let Some(pos) = visitor.reserved {
+        let position = parsed.text_info_lazy().line_and_column_display(pos);
+        bail!(
+            "{}:{}:{}: `{}` is reserved",
+            specifier,
+            position.line_number,
+            position.column_number,
+            TICK_FUNCTION
+        );
+    }
+
+    // Text closing a node goes before text opening another at the same
+    // index, and closes inner nodes, which were visited last, first.
+    let mut inserts = visitor.inserts;
+    inserts.sort_by_key(|&(index, order, _)| (index, order));
+
+    let text = parsed.text();
+    let mut code = String::with_capacity(text.len() + inserts.len() * TICK.len());
+    let mut last = 0;
+    for (index, _, insert) in inserts {
+        code.push_str(&text[last..index]);
+        code.push_str(insert);
+        last = index;
+    }
+    code.push_str(&text[last..]);
+    Ok(code)
+}
+
+fn byte_index(pos: SourcePos) -> usize {
+    pos.as_byte_index(StartSourcePos::START_SOURCE_POS)
+}
+
+struct Instrumenter {
+    /// Text to insert, as `(byte index, order, text)`.
+    inserts: Vec<(usize, i64, &'static str)>,
+    /// First use of `TICK_FUNCTION` in the source, if any.
+    reserved: Option<SourcePos>,
+}
+
+impl Instrumenter {
+    fn open(&mut self, index: usize, text: &'static str) {
+        let order = self.inserts.len() as i64 + 1;
+        self.inserts.push((index, order, text));
+    }
+
+    fn close(&mut self, index: usize, text: &'static str) {
+        let order = -(self.inserts.len() as i64 + 1);
+        self.inserts.push((index, order, text));
+    }
+
+    fn tick_block(&mut self, block: &BlockStmt) {
+        self.open(byte_index(block.start()) + 1, TICK);
+    }
+
+    fn tick_body(&mut self, body: &Stmt) {
+        match body {
+            Stmt::Block(block) => self.tick_block(block),
+            _ => {
+                self.open(byte_index(body.start()), "{ __comet_tick(); ");
+                self.close(byte_index(body.end()), " }");
+            }
+        }
+    }
+}
+
+impl Visit for Instrumenter {
+    fn visit_ident(&mut self, ident: &Ident) {
+        if ident.sym.as_ref() == TICK_FUNCTION && self.reserved.is_none() {
+            self.reserved = Some(ident.start());
+        }
+    }
+
+    fn visit_for_stmt(&mut self, stmt: &ForStmt) {
+        self.tick_body(&stmt.body);
+        stmt.visit_children_with(self);
+    }
+
+    fn visit_for_in_stmt(&mut self, stmt: &ForInStmt) {
+        self.tick_body(&stmt.body);
+        stmt.visit_children_with(self);
+    }
+
+    fn visit_for_of_stmt(&mut self, stmt: &ForOfStmt) {
+        self.tick_body(&stmt.body);
+        stmt.visit_children_with(self);
+    }
+
+    fn visit_while_stmt(&mut self, stmt: &WhileStmt) {
+        self.tick_body(&stmt.body);
+        stmt.visit_children_with(self);
+    }
+
+    fn visit_do_while_stmt(&mut self, stmt: &DoWhileStmt) {
+        self.tick_body(&stmt.body);
+        stmt.visit_children_with(self);
+    }
+
+    fn visit_function(&mut self, function: &Function) {
+        if let Some(body) = &function.body {
+            self.tick_block(body);
+        }
+        function.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, constructor: &Constructor) {
+        if let Some(body) = &constructor.body {
+            self.tick_block(body);
+        }
+        constructor.visit_children_with(self);
+    }
+
+    fn visit_getter_prop(&mut self, prop: &GetterProp) {
+        if let Some(body) = &prop.body {
+            self.tick_block(body);
+        }
+        prop.visit_children_with(self);
+    }
+
+    fn visit_setter_prop(&mut self, prop: &SetterProp) {
+        if let Some(body) = &prop.body {
+            self.tick_block(body);
+        }
+        prop.visit_children_with(self);
+    }
+
+    fn visit_arrow_expr(&mut self, arrow: &ArrowExpr) {
+        match arrow.body.as_ref() {
+            BlockStmtOrExpr::BlockStmt(block) => self.tick_block(block),
+            BlockStmtOrExpr::Expr(expr) => {
+                self.open(byte_index(expr.start()), "(__comet_tick(), ");
+                self.close(byte_index(expr.end()), ")");
+            }
+        }
+        arrow.visit_children_with(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn instrument_js(source: &str) -> Result<String, AnyError> {
+        let specifier = ModuleSpecifier::parse("file:///meter.js").unwrap();
+        instrument(&specifier, MediaType::JavaScript, source.to_string())
+    }
+
+    #[test]
+    fn test_instrument() {
+        let cases = [
+            ("while (a) { b(); }", "while (a) {__comet_tick(); b(); }"),
+            (
+                "for (;;) while (a) b();",
+                "for (;;) { __comet_tick(); while (a) { __comet_tick(); b(); } }",
+            ),
+            (
+                "function f() { return 1; }",
+                "function f() {__comet_tick(); return 1; }",
+            ),
+            (
+                "const f = () => ({ a: 1 });",
+                "const f = () => (__comet_tick(), ({ a: 1 }));",
+            ),
+            (
+                "while (a) () => b",
+                "while (a) { __comet_tick(); () => (__comet_tick(), b) }",
+            ),
+            (
+                "class A { constructor() {} get b() { return 1; } }",
+                "class A { constructor() {__comet_tick();} get b() {__comet_tick(); return 1; } }",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(instrument_js(source).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_instrument_rejects_reserved() {
+        let err = instrument_js("const x = 1;\nconst __comet_tick = () => {};").unwrap_err();
+        assert!(err.to_string().ends_with("2:7: `__comet_tick` is reserved"));
+    }
+}
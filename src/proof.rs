@@ -0,0 +1,217 @@
+//! Existence and non-existence proofs of store keys against the app hash.
+//!
+//! Proofs follow the shape of ICS-23: an existence proof carries the key, the
+//! value and the sibling hashes on the path to its leaf in the sparse Merkle
+//! tree, see `merkle`, and a non-existence proof carries the sibling hashes on
+//! the path to where the key would be, which ends at an empty subtree or at
+//! the leaf of another key. Each `ProofOp` is verified on its own against the
+//! app hash of the queried height, see `verify`.
+//!
+//! A `ProofOp` has type `EXISTENCE_PROOF_TYPE` or `NON_EXISTENCE_PROOF_TYPE`,
+//! the proven key as `key`, and the protobuf encoded `ExistenceProof` or
+//! `NonExistenceProof` as `data`. With `H` being SHA-256, a client recomputes
+//! the app hash as follows:
+//!
+//! - the path of a key is the bits of `H(key)`, most significant bit of the
+//!   first byte first, 0 going left and 1 going right;
+//! - the leaf of a pair hashes to `H(0x00 || H(key) || H(value))` and an inner
+//!   node to `H(0x01 || left || right)`, an empty subtree being 32 zero bytes;
+//! - starting from the bottom of the path, that is the leaf of the key, the
+//!   `Leaf` of a non-existence proof or an empty subtree, the hash is combined
+//!   with the siblings from the last one up, the sibling at index `i` being on
+//!   the side opposite to bit `i` of the path.
+//!
+//! A non-existence proof ending at another leaf is only valid if that leaf
+//! shares the first `siblings.len()` bits of the path of the key, as a leaf
+//! sits at the shallowest depth where no other key shares its path.
+
+use deno_core::error::AnyError;
+use prost::Message;
+use tendermint_proto::crypto::ProofOp;
+
+use crate::merkle;
+
+pub const EXISTENCE_PROOF_TYPE: &str = "comet-deno:exist";
+pub const NON_EXISTENCE_PROOF_TYPE: &str = "comet-deno:absent";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExistenceProof {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+    /// Sibling hashes on the path to the leaf of the key, from the root down.
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    pub siblings: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NonExistenceProof {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    /// Sibling hashes on the path to where the key would be, from the root
+    /// down.
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub siblings: Vec<Vec<u8>>,
+    /// Leaf of another key the path ends at, if it does not end at an empty
+    /// subtree.
+    #[prost(message, optional, tag = "3")]
+    pub leaf: Option<Leaf>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Leaf {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key_hash: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value_hash: Vec<u8>,
+}
+
+/// Proves the presence of `key` holding `value`, or its absence if `None`,
+/// with `path`, its path in the tree of the proven height, see
+/// `Store::prove_at`.
+pub fn prove(key: &[u8], value: Option<Vec<u8>>, path: merkle::Proof) -> ProofOp {
+    let siblings = path.siblings.iter().map(|h| h.to_vec()).collect();
+    match value {
+        Some(value) => ProofOp {
+            r#type: EXISTENCE_PROOF_TYPE.to_string(),
+            key: key.to_vec(),
+            data: ExistenceProof {
+                key: key.to_vec(),
+                value,
+                siblings,
+            }
+            .encode_to_vec(),
+        },
+        None => ProofOp {
+            r#type: NON_EXISTENCE_PROOF_TYPE.to_string(),
+            key: key.to_vec(),
+            data: NonExistenceProof {
+                key: key.to_vec(),
+                siblings,
+                leaf: path.leaf.map(|(key_hash, value_hash)| Leaf {
+                    key_hash: key_hash.to_vec(),
+                    value_hash: value_hash.to_vec(),
+                }),
+            }
+            .encode_to_vec(),
+        },
+    }
+}
+
+/// Verifies `op` against `root`, returning the proven value of the key, or
+/// `None` if the key was proven absent, as light clients do.
+pub fn verify(root: &[u8], op: &ProofOp) -> Result<Option<Vec<u8>>, AnyError> {
+    let root = merkle::Hash::try_from(root)?;
+    let hashes = |siblings: &[Vec<u8>]| {
+        siblings
+            .iter()
+            .map(|h| merkle::Hash::try_from(h.as_slice()))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    match op.r#type.as_str() {
+        EXISTENCE_PROOF_TYPE => {
+            let proof = ExistenceProof::decode(op.data.as_slice())?;
+            if proof.key != op.key {
+                return Err(AnyError::msg("proof is for another key"));
+            }
+            let path = merkle::Proof {
+                siblings: hashes(&proof.siblings)?,
+                leaf: Some((
+                    merkle::key_hash(&proof.key),
+                    merkle::value_hash(&proof.value),
+                )),
+            };
+            if !merkle::verify(&root, &proof.key, Some(&proof.value), &path) {
+                return Err(AnyError::msg("merkle path does not match root"));
+            }
+            Ok(Some(proof.value))
+        }
+        NON_EXISTENCE_PROOF_TYPE => {
+            let proof = NonExistenceProof::decode(op.data.as_slice())?;
+            if proof.key != op.key {
+                return Err(AnyError::msg("proof is for another key"));
+            }
+            let leaf = match proof.leaf {
+                Some(leaf) => Some((
+                    merkle::Hash::try_from(leaf.key_hash.as_slice())?,
+                    merkle::Hash::try_from(leaf.value_hash.as_slice())?,
+                )),
+                None => None,
+            };
+            let path = merkle::Proof {
+                siblings: hashes(&proof.siblings)?,
+                leaf,
+            };
+            if !merkle::verify(&root, &proof.key, None, &path) {
+                return Err(AnyError::msg("merkle path does not match root"));
+            }
+            Ok(None)
+        }
+        other => Err(AnyError::msg(format!("unknown proof type {}", other))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        merkle::{Node, NodeKey},
+        store::{MemoryStore, Store},
+    };
+
+    #[tokio::test]
+    async fn test_prove_and_verify() {
+        let mut store = MemoryStore::new();
+        for (k, v) in [("a", "1"), ("c", "3"), ("d", "4"), ("f", "6"), ("g", "7")] {
+            store.set(k.into(), v.into()).await.unwrap();
+        }
+        store.commit(1, vec![]).await.unwrap();
+        store.set("b".into(), "2".into()).await.unwrap();
+        store.delete("c".into()).await.unwrap();
+        store.commit(2, vec![]).await.unwrap();
+
+        let store = &store;
+        let prove_at = |key: &str, height| {
+            let key = key.as_bytes().to_vec();
+            async move {
+                let value = store.get_at(key.clone(), height).await.unwrap();
+                let path = store.prove_at(key.clone(), height).await.unwrap();
+                prove(&key, value, path)
+            }
+        };
+
+        for (height, present, absent) in [
+            (1, ["a", "c", "g"], ["0", "b", "z"]),
+            (2, ["a", "b", "g"], ["0", "c", "z"]),
+        ] {
+            let root = store.root_hash_at(height).await.unwrap();
+            for k in present {
+                let value = verify(&root, &prove_at(k, height).await).unwrap();
+                assert!(value.is_some());
+                assert_eq!(value, store.get_at(k.into(), height).await.unwrap());
+            }
+            for k in absent {
+                assert_eq!(verify(&root, &prove_at(k, height).await).unwrap(), None);
+            }
+        }
+
+        let root = store.root_hash_at(2).await.unwrap();
+        let forged = prove_at("c", 1).await;
+        assert!(verify(&root, &forged).is_err());
+        let mut forged = prove_at("a", 2).await;
+        forged.key = b"b".to_vec();
+        assert!(verify(&root, &forged).is_err());
+        let mut forged = prove_at("e", 2).await;
+        forged.r#type = EXISTENCE_PROOF_TYPE.to_string();
+        assert!(verify(&root, &forged).is_err());
+
+        let path = merkle::prove(&BTreeMap::<NodeKey, Node>::new(), None, b"a").unwrap();
+        assert_eq!(
+            verify(&merkle::EMPTY, &prove(b"a", None, path)).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::bail;
+
+/// Which historical heights to keep queryable once they are committed over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pruning {
+    /// Keep the state of every height.
+    Nothing,
+    /// Keep only the state of the latest height.
+    Everything,
+    /// Keep the state of the `keep_recent` latest heights, plus that of every
+    /// height that is a multiple of `keep_every` unless it is 0.
+    Custom { keep_recent: i64, keep_every: i64 },
+}
+
+impl Pruning {
+    pub fn new(strategy: &str, keep_recent: i64, keep_every: i64) -> anyhow::Result<Self> {
+        match strategy {
+            "nothing" => Ok(Self::Nothing),
+            "everything" => Ok(Self::Everything),
+            "custom" if keep_recent < 1 => bail!("pruning must keep at least the latest height"),
+            "custom" if keep_every < 0 => bail!("invalid pruning interval {}", keep_every),
+            "custom" => Ok(Self::Custom {
+                keep_recent,
+                keep_every,
+            }),
+            _ => bail!("unknown pruning strategy {:?}", strategy),
+        }
+    }
+
+    /// Lowest height whose state is still kept after committing `latest`. 0
+    /// means every height.
+    pub fn retain_height(&self, latest: i64) -> i64 {
+        match *self {
+            Self::Nothing => 0,
+            Self::Everything => latest,
+            Self::Custom { keep_recent, .. } => (latest - keep_recent + 1).max(1),
+        }
+    }
+
+    /// Whether the state at `height` is kept even below the retain height.
+    pub fn keeps(&self, height: i64) -> bool {
+        match *self {
+            Self::Custom { keep_every, .. } if keep_every > 0 => height % keep_every == 0,
+            _ => false,
+        }
+    }
+}
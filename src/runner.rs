@@ -1,84 +1,373 @@
 use core::str;
-use std::sync::{
-    mpsc::{Receiver, Sender},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc,
+    },
 };
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 
 use tendermint_abci::Error;
-use tendermint_proto::abci::{ExecTxResult, ResponseQuery};
+use tendermint_proto::{
+    abci::{
+        response_apply_snapshot_chunk, response_offer_snapshot, ExecTxResult,
+        ResponseApplySnapshotChunk, ResponseQuery, Snapshot,
+    },
+    crypto::ProofOps,
+};
 use tokio::sync::Mutex;
 
 use crate::{
-    runtime,
-    service::MAX_VARINT_LENGTH,
-    store::{MemoryStore, Store},
+    gas::{GasMeter, OutOfGas, QUERY_GAS_LIMIT},
+    genesis::{params_key, Genesis, CHAIN_ID_KEY, GENESIS_SENDER},
+    loader::ModuleCache,
+    proof,
+    pruning::Pruning,
+    runtime::{self, BlockContext, HeapLimitExceeded, Limits, PoolOptions, RuntimePool, TimedOut},
+    script::Permissions,
+    snapshot::{Applied, Snapshots},
+    store::{CacheStore, HistoricalStore, ListOptions, Store},
 };
 
+/// `ExecTxResult.code` of a transaction whose script failed.
+pub const CODE_SCRIPT_ERROR: u32 = 1;
+/// `ExecTxResult.code` of a transaction that ran out of gas.
+pub const CODE_OUT_OF_GAS: u32 = 2;
+/// `ExecTxResult.code` of a transaction whose script ran past the wall-clock
+/// limit, answered just before the node halts.
+pub const CODE_TIMED_OUT: u32 = 3;
+/// `ExecTxResult.code` of a transaction terminated for using too much memory.
+pub const CODE_HEAP_LIMIT_EXCEEDED: u32 = 4;
+
 #[derive(Debug)]
 pub enum RunnerCommand {
     #[allow(dead_code)]
     GetInfo { result_tx: Sender<(i64, Vec<u8>)> },
+    /// Answers with the app hash of the genesis state.
+    InitChain {
+        chain_id: String,
+        initial_height: i64,
+        app_state: Bytes,
+        init_script: Option<String>,
+        /// Genesis time, seen by the init script as its block.
+        block: BlockContext,
+        result_tx: Sender<anyhow::Result<Vec<u8>>>,
+    },
     Query {
         path: String,
         request: Bytes,
+        height: i64,
+        prove: bool,
+        result_tx: Sender<anyhow::Result<ResponseQuery>>,
+    },
+    QueryKey {
+        key: Bytes,
+        height: i64,
+        prove: bool,
         result_tx: Sender<anyhow::Result<ResponseQuery>>,
     },
     Execute {
         path: String,
         sender: String,
         request: serde_json::Value,
+        gas_limit: u64,
+        block: BlockContext,
         result_tx: Sender<anyhow::Result<ExecTxResult>>,
     },
+    /// Ends the block being finalized, answering with its app hash.
+    FinalizeBlock {
+        result_tx: Sender<anyhow::Result<Vec<u8>>>,
+    },
+    /// Answers with the committed height and the retain height.
     #[allow(dead_code)]
-    Commit { result_tx: Sender<(i64, Vec<u8>)> },
+    Commit { result_tx: Sender<(i64, i64)> },
+    ListSnapshots {
+        result_tx: Sender<anyhow::Result<Vec<Snapshot>>>,
+    },
+    LoadSnapshotChunk {
+        height: u64,
+        format: u32,
+        chunk: u32,
+        result_tx: Sender<anyhow::Result<Vec<u8>>>,
+    },
+    OfferSnapshot {
+        snapshot: Snapshot,
+        app_hash: Bytes,
+        result_tx: Sender<response_offer_snapshot::Result>,
+    },
+    ApplySnapshotChunk {
+        index: u32,
+        chunk: Bytes,
+        sender: String,
+        result_tx: Sender<anyhow::Result<ResponseApplySnapshotChunk>>,
+    },
 }
 
 pub struct Runner {
     rx: Receiver<RunnerCommand>,
+    /// Last committed state, which is what queries are served from.
     store: Arc<Mutex<dyn Store>>,
+    /// Writes of the block being finalized, applied to `store` on commit.
+    block: Arc<Mutex<CacheStore>>,
     height: i64,
     app_hash: Vec<u8>,
+    /// App hash of the finalized block awaiting commit.
+    finalized: Option<Vec<u8>>,
+    /// Cross-namespace grants, keyed by script name.
+    permissions: Arc<HashMap<String, Permissions>>,
+    /// Names of the registered scripts, by kind.
+    scripts: Arc<HashMap<String, Vec<String>>>,
+    pruning: Pruning,
+    snapshots: Snapshots,
+    limits: Limits,
+    /// Runtimes to keep warm between commands, and where to cache scripts.
+    pool_options: PoolOptions,
+    /// Modules compiled when the node started, handed to the pool once the
+    /// runner runs.
+    cache: Option<ModuleCache>,
 }
 
 impl Runner {
-    pub fn new(rx: Receiver<RunnerCommand>) -> Self {
+    pub fn new(
+        rx: Receiver<RunnerCommand>,
+        store: Arc<Mutex<dyn Store>>,
+        permissions: HashMap<String, Permissions>,
+        scripts: HashMap<String, Vec<String>>,
+        pruning: Pruning,
+        snapshots: Snapshots,
+        limits: Limits,
+        pool_options: PoolOptions,
+        cache: ModuleCache,
+    ) -> Self {
         Self {
             rx,
             height: 0,
-            app_hash: vec![0_u8; MAX_VARINT_LENGTH],
-            store: Arc::new(Mutex::new(MemoryStore::new())),
+            app_hash: vec![],
+            finalized: None,
+            permissions: Arc::new(permissions),
+            scripts: Arc::new(scripts),
+            pruning,
+            snapshots,
+            limits,
+            pool_options,
+            cache: Some(cache),
+            block: Arc::new(Mutex::new(CacheStore::new(Arc::clone(&store)))),
+            store,
+        }
+    }
+
+    /// Restores the last committed height and app hash from the store, so a
+    /// restarted node resumes where it left off instead of at genesis.
+    async fn restore(&mut self) -> anyhow::Result<()> {
+        if let Some((height, app_hash)) = self.store.lock().await.last_commit().await? {
+            tracing::info!("restored state at height {}", height);
+            self.height = height;
+            self.app_hash = app_hash;
         }
+        Ok(())
     }
 
     async fn handle_info(&self) -> anyhow::Result<(i64, Vec<u8>)> {
         Ok((self.height, self.app_hash.clone()))
     }
 
-    async fn handle_query(&self, path: String, request: Bytes) -> anyhow::Result<ResponseQuery> {
+    /// Writes the genesis state into the first block and runs the init script
+    /// against it, returning the resulting app hash.
+    async fn handle_init_chain(
+        &mut self,
+        chain_id: String,
+        initial_height: i64,
+        app_state: Bytes,
+        init_script: Option<String>,
+        block: BlockContext,
+        pool: &mut RuntimePool,
+    ) -> anyhow::Result<Vec<u8>> {
         tracing::info!(
-            "handle_query: path={}, request={}",
+            "handle_init_chain: chain_id={}, initial_height={}",
+            chain_id,
+            initial_height
+        );
+
+        // Genesis and the writes of the init script only reach the block once
+        // the script succeeds, so a failed init chain leaves nothing behind.
+        let (genesis, request) = Genesis::parse(&app_state)?;
+        let scratch = Arc::new(Mutex::new(CacheStore::new(
+            Arc::clone(&self.block) as Arc<Mutex<dyn Store>>
+        )));
+        {
+            let mut scratch = scratch.lock().await;
+            scratch
+                .set(CHAIN_ID_KEY.to_vec(), chain_id.into_bytes())
+                .await?;
+            for (script, params) in &genesis.params {
+                scratch
+                    .set(params_key(script), serde_json::to_vec(params)?)
+                    .await?;
+            }
+            for (namespace, pairs) in genesis.state {
+                for (key, value) in pairs {
+                    let key = format!("{}/{}", namespace, key).into_bytes();
+                    scratch.set(key, value.into_bytes()).await?;
+                }
+            }
+        }
+
+        if let Some(path) = init_script {
+            let runtime_res = runtime::run(
+                Arc::clone(&scratch) as Arc<Mutex<dyn Store>>,
+                runtime::RuntimeMode::Execute(block),
+                GENESIS_SENDER,
+                request,
+                &path,
+                Arc::clone(&self.permissions),
+                Arc::clone(&self.scripts),
+                &mut GasMeter::unlimited(),
+                pool,
+            )
+            .await?;
+            tracing::info!("init script result: {}", runtime_res);
+        }
+        scratch.lock().await.flush().await?;
+
+        // The first block committed is `initial_height`.
+        self.height = initial_height.max(1) - 1;
+        Ok(self.block.lock().await.root_hash().await?)
+    }
+
+    /// Returns the store to serve a query at `height` from, where 0 means the
+    /// latest committed height.
+    async fn store_at(&self, height: i64) -> anyhow::Result<(i64, Arc<Mutex<dyn Store>>)> {
+        if height == 0 || height == self.height {
+            return Ok((self.height, Arc::clone(&self.store)));
+        }
+        if height > self.height {
+            anyhow::bail!(
+                "height {} is in the future, latest height is {}",
+                height,
+                self.height
+            );
+        }
+
+        let store = self.store.lock().await;
+        if !store.retains(height).await? {
+            anyhow::bail!(
+                "height {} is not available, earliest available height is {}",
+                height,
+                store.earliest_height().await?.unwrap_or(self.height)
+            );
+        }
+
+        Ok((
+            height,
+            Arc::new(Mutex::new(HistoricalStore::new(
+                Arc::clone(&self.store),
+                height,
+            ))),
+        ))
+    }
+
+    /// Proves the presence or absence of every key in `keys` against the app
+    /// hash of the committed `height`. Every proof is checked the way clients
+    /// do before it is returned.
+    async fn prove(
+        &self,
+        height: i64,
+        keys: impl IntoIterator<Item = Vec<u8>>,
+    ) -> anyhow::Result<ProofOps> {
+        let store = self.store.lock().await;
+        if store.last_commit().await?.is_none() {
+            anyhow::bail!("no block committed yet, there is no app hash to prove against");
+        }
+
+        let root = store.root_hash_at(height).await?;
+        let mut ops = vec![];
+        for key in keys {
+            let value = store.get_at(key.clone(), height).await?;
+            let path = store.prove_at(key.clone(), height).await?;
+            let op = proof::prove(&key, value, path);
+            proof::verify(&root, &op).map_err(|err| {
+                anyhow::anyhow!("proof of key {:?} at height {}: {}", key, height, err)
+            })?;
+            ops.push(op);
+        }
+        Ok(ProofOps { ops })
+    }
+
+    async fn handle_query_key(
+        &self,
+        key: Bytes,
+        height: i64,
+        prove: bool,
+    ) -> anyhow::Result<ResponseQuery> {
+        let key = key.to_vec();
+        tracing::info!("handle_query_key: key={:?}, height={}", key, height);
+
+        let (height, store) = self.store_at(height).await?;
+        let value = store.lock().await.get(key.clone()).await?;
+        let proof_ops = if prove {
+            Some(self.prove(height, [key.clone()]).await?)
+        } else {
+            None
+        };
+
+        Ok(ResponseQuery {
+            log: match value {
+                Some(_) => "exists".to_string(),
+                None => "does not exist".to_string(),
+            },
+            key: key.into(),
+            value: value.unwrap_or_default().into(),
+            proof_ops,
+            height,
+            ..Default::default()
+        })
+    }
+
+    async fn handle_query(
+        &self,
+        path: String,
+        request: Bytes,
+        height: i64,
+        prove: bool,
+        pool: &mut RuntimePool,
+    ) -> anyhow::Result<ResponseQuery> {
+        tracing::info!(
+            "handle_query: path={}, height={}, request={}",
             path,
+            height,
             str::from_utf8(&request)?
         );
 
+        let (height, store) = self.store_at(height).await?;
+
         let runtime_res = runtime::run(
-            Arc::clone(&self.store),
-            runtime::RuntimeMode::Query,
+            Arc::clone(&store),
+            runtime::RuntimeMode::Query(None),
             "<querier>",
             serde_json::from_slice(&request)?,
             &path,
+            Arc::clone(&self.permissions),
+            Arc::clone(&self.scripts),
+            &mut GasMeter::new(QUERY_GAS_LIMIT),
+            pool,
         )
         .await?;
 
         let runner_res = match runtime_res {
-            runtime::RuntimeRunResult::Query(res) => {
+            runtime::RuntimeRunResult::Query(res, reads) => {
                 let v = serde_json::to_vec(&res)?;
+                let proof_ops = if prove {
+                    Some(self.prove(height, reads).await?)
+                } else {
+                    None
+                };
 
                 ResponseQuery {
                     value: v.into(),
-                    height: self.height,
+                    proof_ops,
+                    height,
                     ..Default::default()
                 }
             }
@@ -93,68 +382,415 @@ impl Runner {
         path: String,
         sender: String,
         request: serde_json::Value,
+        gas_limit: u64,
+        block: BlockContext,
+        pool: &mut RuntimePool,
     ) -> anyhow::Result<ExecTxResult> {
         tracing::info!(
-            "handle_execute: path={}, sender={}, request={}",
+            "handle_execute: path={}, sender={}, gas_limit={}, request={}",
             path,
             sender,
+            gas_limit,
             request
         );
 
+        let mut gas = GasMeter::new(gas_limit);
         let runtime_res = runtime::run(
-            Arc::clone(&self.store),
-            runtime::RuntimeMode::Execute,
+            Arc::clone(&self.block) as Arc<Mutex<dyn Store>>,
+            runtime::RuntimeMode::Execute(block),
             &sender,
             request,
             &path,
+            Arc::clone(&self.permissions),
+            Arc::clone(&self.scripts),
+            &mut gas,
+            pool,
         )
-        .await?;
+        .await;
 
         let runner_res = match runtime_res {
-            runtime::RuntimeRunResult::Execute(events) => ExecTxResult {
+            Ok(runtime::RuntimeRunResult::Execute(events)) => ExecTxResult {
                 events,
                 ..Default::default()
             },
-            _ => panic!("unexpected runtime result"),
+            Ok(_) => panic!("unexpected runtime result"),
+            // Whether a run times out depends on the node, see `Limits::timeout`.
+            Err(err) if err.is::<TimedOut>() => return Err(err),
+            Err(err) => ExecTxResult {
+                code: if err.is::<OutOfGas>() {
+                    CODE_OUT_OF_GAS
+                } else if err.is::<HeapLimitExceeded>() {
+                    CODE_HEAP_LIMIT_EXCEEDED
+                } else {
+                    CODE_SCRIPT_ERROR
+                },
+                log: err.to_string(),
+                ..Default::default()
+            },
         };
 
-        Ok(runner_res)
+        Ok(ExecTxResult {
+            gas_wanted: gas.limit() as i64,
+            gas_used: gas.used() as i64,
+            ..runner_res
+        })
     }
 
-    async fn handle_commit(&mut self) -> anyhow::Result<(i64, Vec<u8>)> {
-        // As in the Go-based key/value store, simply encode the number of
-        // items as the "app hash"
-        let mut app_hash = BytesMut::with_capacity(MAX_VARINT_LENGTH);
-        prost::encoding::encode_varint(self.store.lock().await.len().await? as u64, &mut app_hash);
-        self.app_hash = app_hash.to_vec();
-        self.height += 1;
-        Ok((self.height, self.app_hash.clone()))
+    /// Computes the app hash of the block being finalized, once every one of
+    /// its transactions was executed.
+    async fn handle_finalize_block(&mut self) -> anyhow::Result<Vec<u8>> {
+        let app_hash = self.block.lock().await.root_hash().await?;
+        self.finalized = Some(app_hash.clone());
+        Ok(app_hash)
+    }
+
+    /// Persists the finalized block and prunes the heights the pruning
+    /// strategy no longer keeps, returning the new height and the height below
+    /// which CometBFT may prune blocks.
+    async fn handle_commit(&mut self) -> anyhow::Result<(i64, i64)> {
+        let Some(app_hash) = self.finalized.take() else {
+            anyhow::bail!("commit without a finalized block");
+        };
+
+        self.block.lock().await.flush().await?;
+
+        let mut store = self.store.lock().await;
+        let height = self.height + 1;
+        store.commit(height, app_hash.clone()).await?;
+
+        let pruning = self.pruning;
+        let retain_height = pruning.retain_height(height);
+        if retain_height > 0 {
+            store
+                .prune(retain_height, &|height| pruning.keeps(height))
+                .await?;
+        }
+
+        if self.snapshots.should_take(height) {
+            let pairs = store.list(&ListOptions::default()).await?;
+            match self.snapshots.take(height, pairs) {
+                Ok(snapshot) => tracing::info!("took snapshot at height {}", snapshot.height),
+                Err(e) => tracing::error!("failed to take snapshot at height {}: {:?}", height, e),
+            }
+        }
+
+        // Peers restoring a snapshot need the blocks from its height on, so
+        // CometBFT keeps them as long as the snapshot is offered.
+        let mut block_retain_height = retain_height;
+        if block_retain_height > 0 {
+            if let Some(oldest) = self.snapshots.oldest_height()? {
+                block_retain_height = block_retain_height.min(oldest);
+            }
+        }
+
+        self.app_hash = app_hash;
+        self.height = height;
+        Ok((self.height, block_retain_height))
+    }
+
+    async fn handle_apply_snapshot_chunk(
+        &mut self,
+        index: u32,
+        chunk: Bytes,
+        sender: String,
+    ) -> anyhow::Result<ResponseApplySnapshotChunk> {
+        use response_apply_snapshot_chunk::Result as ApplyResult;
+
+        let result = match self.snapshots.apply_chunk(index, chunk.to_vec())? {
+            Applied::Pending => ApplyResult::Accept,
+            Applied::Refetch => {
+                tracing::warn!("chunk {} from {} does not match its hash", index, sender);
+                return Ok(ResponseApplySnapshotChunk {
+                    result: ApplyResult::Retry.into(),
+                    refetch_chunks: vec![index],
+                    reject_senders: vec![sender],
+                });
+            }
+            Applied::Rejected(reason) => {
+                tracing::warn!("rejected snapshot: {}", reason);
+                ApplyResult::RejectSnapshot
+            }
+            Applied::Complete {
+                height,
+                app_hash,
+                pairs,
+            } => {
+                self.store
+                    .lock()
+                    .await
+                    .restore(height, app_hash.clone(), pairs)
+                    .await?;
+                self.block = Arc::new(Mutex::new(CacheStore::new(Arc::clone(&self.store))));
+                self.height = height;
+                self.app_hash = app_hash;
+                tracing::info!("restored snapshot at height {}", height);
+                ApplyResult::Accept
+            }
+        };
+
+        Ok(ResponseApplySnapshotChunk {
+            result: result.into(),
+            ..Default::default()
+        })
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
+        self.restore().await?;
+
+        // Runtimes are not `Send`, so the pool lives on the runner's thread.
+        let cache = self
+            .cache
+            .take()
+            .unwrap_or_else(|| ModuleCache::new(self.pool_options.cache_dir.clone()));
+        let mut pool = RuntimePool::with_cache(self.limits, self.pool_options.clone(), cache);
+
         loop {
-            let cmd = self.rx.recv().map_err(Error::channel_recv)?;
+            let cmd = loop {
+                match self.rx.try_recv() {
+                    Ok(cmd) => break cmd,
+                    // Warm runtimes up while there is nothing else to do.
+                    Err(TryRecvError::Empty) if pool.warm_one() => continue,
+                    Err(_) => break self.rx.recv().map_err(Error::channel_recv)?,
+                }
+            };
 
             match cmd {
                 RunnerCommand::GetInfo { result_tx } => {
                     let res = self.handle_info().await?;
                     result_tx.send(res)?
                 }
+                RunnerCommand::InitChain {
+                    chain_id,
+                    initial_height,
+                    app_state,
+                    init_script,
+                    block,
+                    result_tx,
+                } => result_tx.send(
+                    self.handle_init_chain(
+                        chain_id,
+                        initial_height,
+                        app_state,
+                        init_script,
+                        block,
+                        &mut pool,
+                    )
+                    .await,
+                )?,
                 RunnerCommand::Query {
                     path,
                     request,
+                    height,
+                    prove,
+                    result_tx,
+                } => result_tx.send(
+                    self.handle_query(path, request, height, prove, &mut pool)
+                        .await,
+                )?,
+                RunnerCommand::QueryKey {
+                    key,
+                    height,
+                    prove,
                     result_tx,
-                } => result_tx.send(self.handle_query(path, request).await)?,
+                } => result_tx.send(self.handle_query_key(key, height, prove).await)?,
                 RunnerCommand::Execute {
                     path,
                     sender,
                     request,
+                    gas_limit,
+                    block,
                     result_tx,
-                } => result_tx.send(self.handle_execute(path, sender, request).await)?,
+                } => match self
+                    .handle_execute(path, sender, request, gas_limit, block, &mut pool)
+                    .await
+                {
+                    // No result of the transaction is safe to commit, as other
+                    // nodes may have completed it, so the node halts instead.
+                    Err(err) if err.is::<TimedOut>() => {
+                        let _ = result_tx.send(Ok(ExecTxResult {
+                            code: CODE_TIMED_OUT,
+                            log: err.to_string(),
+                            ..Default::default()
+                        }));
+                        return Err(err);
+                    }
+                    res => result_tx.send(res)?,
+                },
+                RunnerCommand::FinalizeBlock { result_tx } => {
+                    result_tx.send(self.handle_finalize_block().await)?
+                }
                 RunnerCommand::Commit { result_tx } => {
                     result_tx.send(self.handle_commit().await?)?
                 }
+                RunnerCommand::ListSnapshots { result_tx } => {
+                    result_tx.send(self.snapshots.list())?
+                }
+                RunnerCommand::LoadSnapshotChunk {
+                    height,
+                    format,
+                    chunk,
+                    result_tx,
+                } => result_tx.send(self.snapshots.load_chunk(height, format, chunk))?,
+                RunnerCommand::OfferSnapshot {
+                    snapshot,
+                    app_hash,
+                    result_tx,
+                } => result_tx.send(self.snapshots.offer(snapshot, app_hash.to_vec()))?,
+                RunnerCommand::ApplySnapshotChunk {
+                    index,
+                    chunk,
+                    sender,
+                    result_tx,
+                } => {
+                    result_tx.send(self.handle_apply_snapshot_chunk(index, chunk, sender).await)?
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{store::MemoryStore, test_util::TempDir};
+
+    fn runner(scripts_dir: &Path) -> Runner {
+        let (_, rx) = std::sync::mpsc::channel();
+        Runner::new(
+            rx,
+            Arc::new(Mutex::new(MemoryStore::new())),
+            HashMap::new(),
+            HashMap::new(),
+            Pruning::Nothing,
+            Snapshots::new(scripts_dir.join("snapshots"), scripts_dir, 0, 0),
+            Limits::default(),
+            PoolOptions {
+                scripts_dir: scripts_dir.into(),
+                ..Default::default()
+            },
+            ModuleCache::default(),
+        )
+    }
+
+    async fn init_chain(
+        runner: &mut Runner,
+        app_state: serde_json::Value,
+    ) -> anyhow::Result<Vec<u8>> {
+        let init_script = runner.pool_options.scripts_dir.join("init.ts");
+        let mut pool = RuntimePool::new(runner.limits, runner.pool_options.clone());
+        runner
+            .handle_init_chain(
+                "test-chain".to_string(),
+                1,
+                app_state.to_string().into(),
+                Some(init_script.display().to_string()),
+                Default::default(),
+                &mut pool,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_init_chain() {
+        let dir = TempDir::new("init");
+        std::fs::write(dir.join("init.ts"), r#"await store.set("ran", "yes");"#).unwrap();
+
+        let mut runner = runner(&dir);
+        let app_hash = init_chain(
+            &mut runner,
+            json!({
+                "state": { "kv-set": { "name": "eddy" } },
+                "params": { "kv-set": { "max_length": 64 } },
+            }),
+        )
+        .await
+        .unwrap();
+
+        let block = runner.block.lock().await;
+        let get = |key: &[u8]| block.get(key.to_vec());
+        assert_eq!(
+            get(CHAIN_ID_KEY).await.unwrap(),
+            Some(b"test-chain".to_vec())
+        );
+        assert_eq!(get(b"kv-set/name").await.unwrap(), Some(b"eddy".to_vec()));
+        assert_eq!(
+            get(&params_key("kv-set")).await.unwrap(),
+            Some(br#"{"max_length":64}"#.to_vec())
+        );
+        assert_eq!(get(b"init/ran").await.unwrap(), Some(b"yes".to_vec()));
+        assert_eq!(app_hash, block.root_hash().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_init_chain_failure_writes_nothing() {
+        let dir = TempDir::new("init-failure");
+        std::fs::write(dir.join("init.ts"), r#"throw new Error("boom");"#).unwrap();
+
+        let mut runner = runner(&dir);
+        let res = init_chain(
+            &mut runner,
+            json!({ "state": { "kv-set": { "name": "eddy" } } }),
+        )
+        .await;
+        assert!(res.is_err());
+        let block = runner.block.lock().await;
+        assert_eq!(block.get(CHAIN_ID_KEY.to_vec()).await.unwrap(), None);
+        assert_eq!(block.get(b"kv-set/name".to_vec()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_init_chain_rejects_reserved_namespace() {
+        let dir = TempDir::new("init-reserved");
+        std::fs::write(dir.join("init.ts"), "").unwrap();
+
+        let mut runner = runner(&dir);
+        let res = init_chain(
+            &mut runner,
+            json!({ "state": { ".chain": { "chain_id": "forged" } } }),
+        )
+        .await;
+        assert!(res.is_err());
+        let block = runner.block.lock().await;
+        assert_eq!(block.get(CHAIN_ID_KEY.to_vec()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_query_key_proof() {
+        let mut runner = runner(Path::new("scripts"));
+        let err = runner
+            .handle_query_key(b"kv-set/name".to_vec().into(), 0, true)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("no block committed yet"),
+            "{}",
+            err
+        );
+
+        let key = b"kv-set/name".to_vec();
+        runner
+            .block
+            .lock()
+            .await
+            .set(key.clone(), b"eddy".to_vec())
+            .await
+            .unwrap();
+        let app_hash = runner.handle_finalize_block().await.unwrap();
+        runner.handle_commit().await.unwrap();
+
+        let res = runner
+            .handle_query_key(key.clone().into(), 0, true)
+            .await
+            .unwrap();
+        assert_eq!(res.height, 1);
+        let ops = res.proof_ops.unwrap().ops;
+        assert_eq!(
+            proof::verify(&app_hash, &ops[0]).unwrap(),
+            Some(b"eddy".to_vec())
+        );
+    }
+}
@@ -35,66 +35,127 @@ const console = {
   },
 };
 
-/**
- * A key-value store interface.
- * @namespace store
- */
-const store = {
-  /**
-   * Sets a value for a given key in the store.
-   * @function
-   * @param {string} k - The key to set.
-   * @param {string} v - The value to set.
-   * @returns {Promise<string>} A promise that resolves to the set value.
-   * @throws {Error} If there's an issue accessing the store or if the key is invalid.
-   */
-  set: (k, v) => ops.op_kv_set(k, v),
+// ==================== Deterministic environment ====================
+//
+// Transactions must compute the same result on every node, so in execute
+// mode the clock reads the block time and `Math.random` is a PRNG seeded from
+// the block hash and the transaction index, and so do the queries they call.
+// Other queries keep the real clock and randomness. Globals whose behavior
+// depends on the garbage collector, the host or other threads are removed, and
+// local time is always UTC.
 
-  /**
-   * Gets the value for a given key from the store.
-   * @function
-   * @param {string} k - The key to get.
-   * @returns {Promise<string>} A promise that resolves to the value associated with the key, or an empty string if not found.
-   * @throws {Error} If there's an issue accessing the store or if the key is invalid.
-   */
-  get: (k) => ops.op_kv_get(k),
+const RealDate = Date;
+const realNow = RealDate.now;
+const realRandom = Math.random;
+
+const now = () => {
+  const block = ops.op_ctx_get_block();
+  return block === null ? realNow() : block.time;
 };
 
-const context = {
-  /**
-   * Emits an event with attributes
-   * @param {{
-   *    type: string;
-   *    attributes: {
-   *      key: string;
-   *      value: string;
-   *      index: boolean
-   *    }[];
-   *  }} event - Event object to emit.
-   */
-  emit: (event) => ops.op_ctx_emit(event),
+/**
+ * Small fast counter PRNG, returning floats in [0, 1).
+ * @param {number[]} seed - Four 32-bit words.
+ */
+const sfc32 = ([a, b, c, d]) => () => {
+  const t = (((a + b) | 0) + d) | 0;
+  d = (d + 1) | 0;
+  a = b ^ (b >>> 9);
+  b = (c + (c << 3)) | 0;
+  c = (c << 21) | (c >>> 11);
+  c = (c + t) | 0;
+  return (t >>> 0) / 4294967296;
+};
 
-  /**
-   * Sends a response message.
-   * @param {object} response - The message to send.
-   * @returns {Promise<void>} A promise that resolves when the message is sent.
-   */
-  respond: (response) => ops.op_ctx_respond(response),
+let random = null;
+Math.random = () => {
+  if (random === null) {
+    const block = ops.op_ctx_get_block();
+    random = block === null ? realRandom : sfc32(block.seed);
+  }
+  return random();
+};
 
-  /**
-   * Retrieves the sender of the current request.
-   * @returns {string} The sender of the current request.
-   */
-  getSender: () => ops.op_ctx_get_sender(),
+function DeterministicDate(...args) {
+  if (new.target === undefined) {
+    return new RealDate(now()).toString();
+  }
+  // Constructing through `new.target` keeps the prototype of subclasses.
+  return Reflect.construct(RealDate, args.length === 0 ? [now()] : args, new.target);
+}
+DeterministicDate.prototype = RealDate.prototype;
+DeterministicDate.now = now;
+DeterministicDate.parse = RealDate.parse;
+DeterministicDate.UTC = RealDate.UTC;
+// Dates and `Date.prototype` must not lead back to the real clock.
+RealDate.prototype.constructor = DeterministicDate;
+RealDate.now = now;
 
-  /**
-   * Fetches a request object.
-   * @returns {T} The request object.
-   * @template T
-   */
-  getRequest: () => ops.op_ctx_get_request(),
+for (const unit of ["FullYear", "Month", "Date", "Day", "Hours", "Minutes", "Seconds", "Milliseconds"]) {
+  RealDate.prototype[`get${unit}`] = RealDate.prototype[`getUTC${unit}`];
+  if (unit !== "Day") {
+    RealDate.prototype[`set${unit}`] = RealDate.prototype[`setUTC${unit}`];
+  }
+}
+RealDate.prototype.getTimezoneOffset = () => 0;
+RealDate.prototype.toString = RealDate.prototype.toUTCString;
+RealDate.prototype.toDateString = function () {
+  return this.toISOString().slice(0, 10);
+};
+RealDate.prototype.toTimeString = function () {
+  return this.toISOString().slice(11);
 };
+RealDate.prototype.toLocaleString = RealDate.prototype.toISOString;
+RealDate.prototype.toLocaleDateString = RealDate.prototype.toDateString;
+RealDate.prototype.toLocaleTimeString = RealDate.prototype.toTimeString;
+Number.prototype.toLocaleString = Number.prototype.toString;
+String.prototype.localeCompare = function (that) {
+  const a = String(this);
+  const b = String(that);
+  return a < b ? -1 : a > b ? 1 : 0;
+};
+
+globalThis.Date = DeterministicDate;
+delete globalThis.WeakRef;
+delete globalThis.FinalizationRegistry;
+delete globalThis.SharedArrayBuffer;
+delete globalThis.Intl;
+delete Atomics.wait;
+delete Atomics.waitAsync;
+// Compiled WebAssembly would escape the instrumentation of every loop and
+// call, see `meter.rs`.
+delete globalThis.WebAssembly;
 
 globalThis.console = console;
-globalThis.store = store;
-globalThis.context = context;
+
+// Every module scripts load is instrumented to call `__comet_tick` at the
+// start of every loop iteration and function body, see `meter.rs`. Ticks are
+// charged to the run's gas in batches, so a script runs out of gas after the
+// same amount of work on every node.
+{
+  const TICK_BATCH = 100;
+  let ticks = 0;
+  let opGasTick = null;
+
+  Object.defineProperty(globalThis, "__comet_tick", {
+    value: () => {
+      if (++ticks === TICK_BATCH) {
+        ticks = 0;
+        opGasTick(TICK_BATCH);
+      }
+    },
+    writable: false,
+    enumerable: false,
+    configurable: false,
+  });
+
+  // Ops only exist once a runtime is created from the snapshot, so
+  // `init_runtime` calls this before any script runs. This file and the
+  // standard library have captured `Deno.core` by then, and scripts must not
+  // reach the raw ops, so `Deno` is removed too.
+  globalThis.__comet_bind_ops = () => {
+    opGasTick = ops.op_gas_tick;
+    delete globalThis.__comet_bind_ops;
+    delete globalThis.Deno;
+  };
+}
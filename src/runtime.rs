@@ -1,32 +1,70 @@
-use std::{borrow::Cow, env, fmt::Display, rc::Rc, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    env,
+    fmt::Display,
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Once,
+    },
+    thread,
+    time::Duration,
+};
 
 use deno_core::{error::AnyError, resolve_path, Extension, JsRuntime, OpDecl, RuntimeOptions};
 use tendermint_proto::abci::Event;
 use tokio::sync::Mutex;
 
 use crate::{
-    loader,
+    gas::{self, GasMeter},
+    loader::{ModuleCache, TsModuleLoader},
     runtime_ops::{
-        op_ctx_emit, op_ctx_get_request, op_ctx_get_sender, op_ctx_respond, op_kv_get, op_kv_set,
+        op_crypto_sha256, op_ctx_call, op_ctx_emit, op_ctx_get_block, op_ctx_get_chain_id,
+        op_ctx_get_params, op_ctx_get_request, op_ctx_get_sender, op_ctx_respond, op_gas_tick,
+        op_kv_delete, op_kv_get, op_kv_list, op_kv_set,
     },
-    store::Store,
+    script::{script_name, Permissions},
+    store::{CacheStore, Store},
 };
 
 pub enum RuntimeMode {
-    Query,
-    Execute,
+    /// A query, within the block of the transaction that called it with
+    /// `context.call`, if any, so that it stays deterministic.
+    Query(Option<BlockContext>),
+    /// Execution of a transaction of the given block.
+    Execute(BlockContext),
+}
+
+/// Deepest chain of `context.call`s, each of which holds a runtime.
+pub(crate) const MAX_CALL_DEPTH: usize = 8;
+
+/// Block a transaction executes in.
+///
+/// In execute mode, `Date` reads the block time instead of the clock and
+/// `Math.random` is seeded from the block hash and the transaction index, so
+/// every node computes the same result.
+#[derive(Debug, Clone, Default)]
+pub struct BlockContext {
+    pub height: i64,
+    /// Block time in milliseconds since the Unix epoch.
+    pub time_ms: i64,
+    pub hash: Vec<u8>,
+    /// Index of the transaction in the block.
+    pub tx_index: u32,
 }
 
 impl RuntimeMode {
     pub fn assert_query(&self) -> Result<(), AnyError> {
-        if !matches!(self, RuntimeMode::Query) {
+        if !matches!(self, RuntimeMode::Query(_)) {
             return Err(AnyError::msg("expected query mode"));
         }
         Ok(())
     }
 
     pub fn assert_execute(&self) -> Result<(), AnyError> {
-        if !matches!(self, RuntimeMode::Execute) {
+        if !matches!(self, RuntimeMode::Execute(_)) {
             return Err(AnyError::msg("expected execute mode"));
         }
         Ok(())
@@ -35,19 +73,71 @@ impl RuntimeMode {
 
 #[derive(Debug)]
 pub enum RuntimeRunResult {
-    Query(serde_json::Value),
+    /// Response of a query script, along with every key it read.
+    Query(serde_json::Value, BTreeSet<Vec<u8>>),
     Execute(Vec<Event>),
 }
 
 impl Display for RuntimeRunResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RuntimeRunResult::Query(res) => write!(f, "query: {}", res),
+            RuntimeRunResult::Query(res, _) => write!(f, "query: {}", res),
             RuntimeRunResult::Execute(events) => write!(f, "execute: {:?}", events),
         }
     }
 }
 
+/// Resources a single run may use besides gas.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Wall-clock time after which the script is terminated.
+    ///
+    /// Gas meters loops and calls too, see `meter`, so scripts run out of it
+    /// long before this. Time is not deterministic across nodes though, so a
+    /// run that trips it has no result that is safe to commit, and the node
+    /// halts instead, see `TimedOut`. It is only a last-resort guard against
+    /// what gas does not account for, e.g. a slow builtin.
+    pub timeout: Duration,
+    /// Size in bytes the V8 heap of a run may grow to.
+    pub max_heap_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            max_heap_bytes: 64 << 20,
+        }
+    }
+}
+
+/// Error of a run that was terminated for exceeding `Limits::timeout`.
+///
+/// Unlike other errors it is not a result of the script, as another node may
+/// well have completed the run in time.
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "script exceeded its time limit of {:?}", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Error of a run that was terminated for exceeding `Limits::max_heap_bytes`.
+#[derive(Debug)]
+pub struct HeapLimitExceeded(pub usize);
+
+impl Display for HeapLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "script exceeded its heap limit of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for HeapLimitExceeded {}
+
 pub struct OpStateContext {
     pub(crate) mode: RuntimeMode,
     pub(crate) store: Arc<Mutex<dyn Store>>,
@@ -55,88 +145,590 @@ pub struct OpStateContext {
     pub(crate) events: Vec<Event>,
     pub(crate) request: serde_json::Value,
     pub(crate) response: Option<serde_json::Value>,
+    /// Keys read through `op_kv_get` and `op_kv_list`, to be proven on request.
+    pub(crate) reads: BTreeSet<Vec<u8>>,
+    /// Namespace the script's keys live in, unless it asks for another one.
+    pub(crate) namespace: String,
+    pub(crate) permissions: Permissions,
+    pub(crate) gas: GasMeter,
+    /// Grants of every script, for the ones called with `context.call`.
+    pub(crate) grants: Arc<HashMap<String, Permissions>>,
+    /// Names of the registered scripts by kind, the only ones `context.call`
+    /// runs.
+    pub(crate) scripts: Arc<HashMap<String, Vec<String>>>,
+    /// Where the runtimes of called scripts come from.
+    pub(crate) pool: RuntimePool,
+    /// Number of `context.call`s the run is nested in.
+    pub(crate) depth: usize,
+    /// Set once a called script was terminated for exceeding its limits. Kept
+    /// so the run fails even if the script caught the error.
+    pub(crate) terminated: Option<AnyError>,
+    /// Handle to the run's isolate, for ops to terminate the script.
+    pub(crate) isolate: v8::IsolateHandle,
+}
+
+impl OpStateContext {
+    /// Store key prefix of `namespace`, or of the script's own namespace if
+    /// `None`, after checking the script was granted access to it.
+    pub(crate) fn namespace_prefix(
+        &self,
+        namespace: Option<&str>,
+        write: bool,
+    ) -> Result<Vec<u8>, AnyError> {
+        let namespace = namespace.unwrap_or(&self.namespace);
+        let allowed = if write {
+            self.permissions.can_write(namespace)
+        } else {
+            self.permissions.can_read(namespace)
+        };
+        let allowed = allowed || namespace == self.namespace;
+        if !allowed {
+            return Err(AnyError::msg(format!(
+                "{} access to namespace {} not permitted",
+                if write { "write" } else { "read" },
+                namespace
+            )));
+        }
+
+        Ok(format!("{}/", namespace).into_bytes())
+    }
+
+    /// Path and mode of the script `name` called with `context.call`: its
+    /// execute script if the caller executes a transaction and it has one, its
+    /// query script otherwise.
+    pub(crate) fn callee(&self, name: &str) -> Result<(String, RuntimeMode), AnyError> {
+        let registered = |kind: &str| {
+            self.scripts
+                .get(kind)
+                .is_some_and(|names| names.iter().any(|n| n == name))
+        };
+
+        let block = match &self.mode {
+            RuntimeMode::Execute(block) => {
+                if registered("execute") {
+                    let path = self.pool.script_path(name, "execute");
+                    return Ok((path, RuntimeMode::Execute(block.clone())));
+                }
+                Some(block.clone())
+            }
+            RuntimeMode::Query(block) => block.clone(),
+        };
+        if !registered("query") {
+            return Err(AnyError::msg(format!("script {} not found", name)));
+        }
+        Ok((
+            self.pool.script_path(name, "query"),
+            RuntimeMode::Query(block),
+        ))
+    }
+
+    /// Store key of `key` in `namespace`, see `namespace_prefix`.
+    pub(crate) fn namespaced_key(
+        &self,
+        namespace: Option<&str>,
+        key: &[u8],
+        write: bool,
+    ) -> Result<Vec<u8>, AnyError> {
+        Ok([self.namespace_prefix(namespace, write)?, key.to_vec()].concat())
+    }
 }
 
 pub const OP_DECL: &[OpDecl] = &[
     op_kv_set(),
     op_kv_get(),
+    op_kv_delete(),
+    op_kv_list(),
     op_ctx_emit(),
     op_ctx_respond(),
     op_ctx_get_sender(),
     op_ctx_get_request(),
+    op_ctx_get_chain_id(),
+    op_ctx_get_params(),
+    op_ctx_get_block(),
+    op_crypto_sha256(),
+    op_ctx_call(),
+    op_gas_tick(),
 ];
 
 static RUNTIME_SNAPSHOT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/RUNJS_SNAPSHOT.bin"));
 
-pub fn init_runtime() -> JsRuntime {
-    JsRuntime::new(RuntimeOptions {
-        module_loader: Some(Rc::new(loader::TsModuleLoader)),
+/// Flags V8 is started with. `build.rs` creates the snapshot with the same
+/// ones, as V8 rejects a snapshot created with different flags.
+///
+/// Code generated from strings, e.g. by `eval`, would escape the
+/// instrumentation of `meter`, and a regular expression could backtrack for
+/// an exponential time without a single tick.
+const V8_FLAGS: &[&str] = &[
+    "--disallow-code-generation-from-strings",
+    "--enable-experimental-regexp-engine-on-excessive-backtracks",
+];
+
+pub fn init_runtime(limits: Limits, loader: Rc<TsModuleLoader>) -> JsRuntime {
+    static SET_V8_FLAGS: Once = Once::new();
+    SET_V8_FLAGS.call_once(|| {
+        // The first argument stands for the program name and is ignored.
+        let args = std::iter::once("").chain(V8_FLAGS.iter().copied());
+        let unrecognized = deno_core::v8_set_flags(args.map(String::from).collect());
+        let unrecognized = &unrecognized[1..];
+        assert!(
+            unrecognized.is_empty(),
+            "unrecognized V8 flags: {:?}",
+            unrecognized
+        );
+    });
+
+    let mut runtime = JsRuntime::new(RuntimeOptions {
+        module_loader: Some(loader),
         startup_snapshot: Some(RUNTIME_SNAPSHOT),
+        create_params: Some(v8::CreateParams::default().heap_limits(0, limits.max_heap_bytes)),
         extensions: vec![Extension {
             name: "deno-kv-ops-ext",
             ops: Cow::Borrowed(OP_DECL),
             ..Default::default()
         }],
         ..Default::default()
-    })
+    });
+
+    // Ops are only registered once the runtime is created from the snapshot,
+    // so `runtime.js` binds the ones it holds on to now, before any script
+    // runs and could replace them.
+    runtime
+        .execute_script("[comet:bind_ops]", "__comet_bind_ops();")
+        .expect("failed to bind ops");
+    runtime
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolOptions {
+    /// Directory scripts, and every module they import, must live in.
+    pub scripts_dir: PathBuf,
+    /// Number of idle runtimes to keep.
+    pub size: usize,
+    /// Directory transpiled scripts are cached in across restarts, if any.
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Runtimes created ahead of time, so a run does not wait for the startup
+/// snapshot to be deserialized. They are only created while the runner is
+/// idle, so the runs of a burst past the pool's size create their own, see
+/// `bench_runtime_pool`.
+///
+/// Every runtime serves a single run and is dropped afterwards, so nothing a
+/// script leaves behind in globals or the module map is seen by the next one.
+/// Runtimes are handed out last created first, since V8 requires isolates to
+/// be dropped in the reverse order of their creation.
+///
+/// Runtimes of the pool share a loader and its `ModuleCache`, so each script
+/// is transpiled and compiled once rather than on every run.
+pub struct RuntimePool {
+    limits: Limits,
+    size: usize,
+    idle: Vec<JsRuntime>,
+    scripts_dir: PathBuf,
+    loader: Rc<TsModuleLoader>,
+}
+
+impl RuntimePool {
+    /// Pool of runtimes created with `limits`.
+    pub fn new(limits: Limits, options: PoolOptions) -> Self {
+        let cache = ModuleCache::new(options.cache_dir.clone());
+        Self::with_cache(limits, options, cache)
+    }
+
+    /// Pool starting out with the modules of `cache`, e.g. the ones
+    /// `precompile` compiled.
+    pub fn with_cache(limits: Limits, options: PoolOptions, cache: ModuleCache) -> Self {
+        Self {
+            limits,
+            size: options.size,
+            idle: Vec::with_capacity(options.size),
+            loader: Rc::new(TsModuleLoader::new(
+                options.scripts_dir.clone(),
+                Rc::new(cache),
+            )),
+            scripts_dir: options.scripts_dir,
+        }
+    }
+
+    /// Pool sharing this one's loader but keeping no idle runtime, for runs
+    /// nested in another one: a runtime created before the outer one could
+    /// not be dropped first.
+    pub fn on_demand(&self) -> Self {
+        Self {
+            limits: self.limits,
+            size: 0,
+            idle: vec![],
+            scripts_dir: self.scripts_dir.clone(),
+            loader: Rc::clone(&self.loader),
+        }
+    }
+
+    /// Path of the script `name` of kind `kind`, e.g. `execute`.
+    pub fn script_path(&self, name: &str, kind: &str) -> String {
+        self.scripts_dir
+            .join(format!("{}.{}.ts", name, kind))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Creates an idle runtime unless the pool is full, returning whether it
+    /// did. Meant to be called while there is nothing else to do.
+    pub fn warm_one(&mut self) -> bool {
+        if self.idle.len() >= self.size {
+            return false;
+        }
+        self.idle
+            .push(init_runtime(self.limits, Rc::clone(&self.loader)));
+        true
+    }
+
+    /// Takes an idle runtime, or creates one if there is none.
+    pub fn take(&mut self) -> JsRuntime {
+        self.idle
+            .pop()
+            .unwrap_or_else(|| init_runtime(self.limits, Rc::clone(&self.loader)))
+    }
 }
 
+/// Loads and instantiates the scripts at `paths` along with their imports,
+/// without evaluating them, so one that fails to parse, transpile or link is
+/// caught when the node starts rather than by the first transaction calling
+/// it. Fails with the error of every such script.
+///
+/// Returns the cache the modules were compiled into, for the runner's pool to
+/// start out with.
+pub fn precompile(
+    paths: &[String],
+    limits: Limits,
+    options: &PoolOptions,
+) -> anyhow::Result<ModuleCache> {
+    let cache = Rc::new(ModuleCache::new(options.cache_dir.clone()));
+    let loader = Rc::new(TsModuleLoader::new(
+        options.scripts_dir.clone(),
+        Rc::clone(&cache),
+    ));
+    let executor = tokio::runtime::Builder::new_current_thread().build()?;
+
+    let mut failures = vec![];
+    for path in paths {
+        let mut runtime = init_runtime(limits, Rc::clone(&loader));
+        let result = executor.block_on(async {
+            let main_module = resolve_path(path, env::current_dir()?.as_path())?;
+            runtime.load_main_es_module(&main_module).await?;
+            Ok::<_, AnyError>(())
+        });
+        if let Err(err) = result {
+            failures.push(format!("{}: {:#}", path, err));
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("scripts failed to compile:\n{}", failures.join("\n"));
+    }
+
+    // Every runtime, and with it every other reference to the cache, is gone.
+    drop(loader);
+    Rc::try_unwrap(cache).map_err(|_| anyhow::anyhow!("module cache still in use"))
+}
+
+/// Runs a script against `store`.
+///
+/// Writes go through a per-run `CacheStore` and only reach `store` once an
+/// execute script completes successfully. If the script fails, its writes
+/// and emitted events are dropped together with the error.
+///
+/// Keys are confined to the namespace named after the script, plus whatever
+/// other namespaces `permissions` grants it.
+///
+/// The run is charged to `gas`, which holds the gas used once it returns,
+/// whether it succeeded or not. Loops and calls are charged too, see `meter`,
+/// and running out of gas fails the run even if the script caught the error.
+/// A script whose heap nears `Limits::max_heap_bytes` is terminated and fails
+/// with `HeapLimitExceeded`, and one still running after the pool's
+/// `Limits::timeout`, which gas should prevent, with `TimedOut`. So does a run
+/// one of whose called scripts was terminated, even if it caught the error.
+///
+/// The run takes a fresh runtime from `pool` and drops it once done.
+///
+/// Scripts called with `context.call` run nested in the caller's run, see
+/// `op_ctx_call`.
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     store: Arc<Mutex<dyn Store>>,
     mode: RuntimeMode,
     sender: &str,
     request: serde_json::Value,
     file_path: &str,
+    permissions: Arc<HashMap<String, Permissions>>,
+    scripts: Arc<HashMap<String, Vec<String>>>,
+    gas: &mut GasMeter,
+    pool: &mut RuntimePool,
 ) -> Result<RuntimeRunResult, AnyError> {
-    let mut runtime = init_runtime();
+    run_nested(
+        store,
+        mode,
+        sender,
+        request,
+        file_path,
+        permissions,
+        scripts,
+        gas,
+        pool,
+        0,
+    )
+    .await
+}
+
+/// `run`, nested in `depth` calls.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_nested(
+    store: Arc<Mutex<dyn Store>>,
+    mode: RuntimeMode,
+    sender: &str,
+    request: serde_json::Value,
+    file_path: &str,
+    permissions: Arc<HashMap<String, Permissions>>,
+    scripts: Arc<HashMap<String, Vec<String>>>,
+    gas: &mut GasMeter,
+    pool: &mut RuntimePool,
+    depth: usize,
+) -> Result<RuntimeRunResult, AnyError> {
+    let source_len = std::fs::metadata(file_path)?.len();
+    gas.consume(
+        gas::EXECUTION_FLAT + gas::SOURCE_PER_BYTE * source_len,
+        "execution",
+    )?;
+
+    let limits = pool.limits();
+    let mut runtime = pool.take();
+
+    // Rather than letting V8 abort the process once the heap is full, the
+    // script is terminated and the heap given enough room to unwind.
+    let heap_exceeded = Arc::new(AtomicBool::new(false));
+    let isolate = runtime.v8_isolate().thread_safe_handle();
+    runtime.add_near_heap_limit_callback({
+        let heap_exceeded = Arc::clone(&heap_exceeded);
+        move |current, _initial| {
+            heap_exceeded.store(true, Ordering::SeqCst);
+            isolate.terminate_execution();
+            current * 2
+        }
+    });
+
+    let cache = Arc::new(Mutex::new(CacheStore::new(store)));
 
     runtime.op_state().borrow_mut().put(OpStateContext {
         mode,
-        store,
+        store: Arc::clone(&cache) as Arc<Mutex<dyn Store>>,
         sender: sender.to_string(),
         events: vec![],
         request,
         response: None,
+        reads: BTreeSet::new(),
+        namespace: script_name(file_path).to_string(),
+        permissions: permissions
+            .get(script_name(file_path))
+            .cloned()
+            .unwrap_or_default(),
+        gas: gas.clone(),
+        grants: permissions,
+        scripts,
+        pool: pool.on_demand(),
+        depth,
+        terminated: None,
+        isolate: runtime.v8_isolate().thread_safe_handle(),
     });
 
-    let main_module = resolve_path(file_path, env::current_dir()?.as_path())?;
-    let module_id = runtime.load_main_es_module(&main_module).await?;
-    let result = runtime.mod_evaluate(module_id);
-    runtime.run_event_loop(Default::default()).await?;
-    result.await?;
+    // The watchdog terminates the isolate unless the run is over, which
+    // drops `done_tx`, before the timeout elapses.
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let isolate = runtime.v8_isolate().thread_safe_handle();
+    let watchdog = thread::spawn(move || {
+        let timed_out = matches!(
+            done_rx.recv_timeout(limits.timeout),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        if timed_out {
+            isolate.terminate_execution();
+        }
+        timed_out
+    });
+
+    let result = evaluate(&mut runtime, file_path).await;
+    drop(done_tx);
+    let timed_out = watchdog.join().expect("watchdog thread panicked");
 
     let ctx = runtime.op_state().borrow_mut().take::<OpStateContext>();
-    if ctx.response.is_none() && matches!(ctx.mode, RuntimeMode::Query) {
+    *gas = ctx.gas;
+    if heap_exceeded.load(Ordering::SeqCst) {
+        return Err(HeapLimitExceeded(limits.max_heap_bytes).into());
+    }
+    if timed_out && result.is_err() {
+        return Err(TimedOut(limits.timeout).into());
+    }
+    if let Some(err) = ctx.terminated {
+        return Err(err);
+    }
+    if let Some(err) = gas.out_of_gas() {
+        return Err(err.clone().into());
+    }
+    result?;
+
+    if ctx.response.is_none() && matches!(ctx.mode, RuntimeMode::Query(_)) {
         return Err(AnyError::msg("respond not called"));
     }
 
     match ctx.mode {
-        RuntimeMode::Query => Ok(RuntimeRunResult::Query(ctx.response.expect("no response"))),
-        RuntimeMode::Execute => Ok(RuntimeRunResult::Execute(ctx.events)),
+        RuntimeMode::Query(_) => Ok(RuntimeRunResult::Query(
+            ctx.response.expect("no response"),
+            ctx.reads,
+        )),
+        RuntimeMode::Execute(_) => {
+            cache.lock().await.flush().await?;
+            Ok(RuntimeRunResult::Execute(ctx.events))
+        }
     }
 }
 
+async fn evaluate(runtime: &mut JsRuntime, file_path: &str) -> Result<(), AnyError> {
+    let main_module = resolve_path(file_path, env::current_dir()?.as_path())?;
+    let module_id = runtime.load_main_es_module(&main_module).await?;
+    let result = runtime.mod_evaluate(module_id);
+    runtime.run_event_loop(Default::default()).await?;
+    result.await
+}
+
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::Arc};
 
-    use runtime::{run, RuntimeMode};
+    use gas::GasMeter;
+    use runtime::{
+        run, HeapLimitExceeded, Limits, PoolOptions, RuntimeMode, RuntimePool, TimedOut,
+    };
+    use script::Permissions;
     use serde_json::json;
     use store::{MemoryStore, Store};
+    use test_util::TempDir;
     use tokio::sync::Mutex;
 
     use crate::*;
 
+    /// Pool loading scripts from `scripts_dir`.
+    fn pool(scripts_dir: impl Into<std::path::PathBuf>, limits: Limits) -> RuntimePool {
+        RuntimePool::new(
+            limits,
+            PoolOptions {
+                scripts_dir: scripts_dir.into(),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Scripts directory of a single test, removed once the test is over, so
+    /// tests running in parallel never see each other's scripts.
+    struct TestScripts {
+        /// Holds the scripts directory, `scripts/`, and nothing else.
+        root: TempDir,
+        limits: Limits,
+        grants: HashMap<String, Permissions>,
+        /// Names of the query and execute scripts, by kind.
+        scripts: HashMap<String, Vec<String>>,
+    }
+
+    impl TestScripts {
+        /// Writes `files`, as `(path, source)` pairs relative to the scripts
+        /// directory, to a scripts directory of their own.
+        fn new(files: &[(&str, &str)]) -> Self {
+            let root = TempDir::new("scripts");
+            let mut scripts = HashMap::<String, Vec<String>>::new();
+            for (path, source) in files {
+                if let [name, kind @ ("query" | "execute"), _] =
+                    path.split('.').collect::<Vec<_>>()[..]
+                {
+                    scripts
+                        .entry(kind.to_string())
+                        .or_default()
+                        .push(name.to_string());
+                }
+                let path = root.join("scripts").join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, source).unwrap();
+            }
+            Self {
+                root,
+                limits: Default::default(),
+                grants: Default::default(),
+                scripts,
+            }
+        }
+
+        fn limits(self, limits: Limits) -> Self {
+            Self { limits, ..self }
+        }
+
+        fn grants(self, grants: impl IntoIterator<Item = (String, Permissions)>) -> Self {
+            Self {
+                grants: grants.into_iter().collect(),
+                ..self
+            }
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.root
+                .join("scripts")
+                .join(file)
+                .to_string_lossy()
+                .into_owned()
+        }
+
+        fn options(&self) -> PoolOptions {
+            PoolOptions {
+                scripts_dir: self.root.join("scripts"),
+                ..Default::default()
+            }
+        }
+
+        /// Runs the script `file` against `store` on a pool of its own.
+        async fn run(
+            &self,
+            store: &Arc<Mutex<dyn Store>>,
+            mode: RuntimeMode,
+            file: &str,
+            gas: &mut GasMeter,
+        ) -> Result<runtime::RuntimeRunResult, deno_core::error::AnyError> {
+            run(
+                Arc::clone(store),
+                mode,
+                "<sender>",
+                json!({}),
+                &self.path(file),
+                Arc::new(self.grants.clone()),
+                Arc::new(self.scripts.clone()),
+                gas,
+                &mut RuntimePool::new(self.limits, self.options()),
+            )
+            .await
+        }
+    }
+
     #[tokio::test]
     async fn test_runtime() {
         let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
 
         let res = run(
             Arc::clone(&store),
-            RuntimeMode::Execute,
+            RuntimeMode::Execute(Default::default()),
             "<sender>",
             json!({"key": "hello", "value": "world"}),
             "../scripts/kv-set.execute.ts",
+            Default::default(),
+            Default::default(),
+            &mut GasMeter::unlimited(),
+            &mut pool("../scripts", Default::default()),
         )
         .await
         .unwrap();
@@ -144,13 +736,540 @@ mod test {
 
         let res = run(
             Arc::clone(&store),
-            RuntimeMode::Query,
+            RuntimeMode::Query(None),
             "<sender>",
             json!({"key": "hello"}),
             "../scripts/kv-get.query.ts",
+            Arc::new(
+                [(
+                    "kv-get".to_string(),
+                    Permissions {
+                        read: ["kv-set".to_string()].into(),
+                        ..Default::default()
+                    },
+                )]
+                .into(),
+            ),
+            Default::default(),
+            &mut GasMeter::unlimited(),
+            &mut pool("../scripts", Default::default()),
         )
         .await
         .unwrap();
         println!("{:?}", res);
     }
+
+    #[tokio::test]
+    async fn test_runtime_rollback() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[(
+            "rollback.execute.ts",
+            r#"await store.set("a", "1"); throw new Error("boom");"#,
+        )]);
+
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "rollback.execute.ts",
+                &mut GasMeter::unlimited(),
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(
+            store.lock().await.get("rollback/a".into()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_out_of_gas() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[(
+            "gas.execute.ts",
+            r#"try { await store.set("a", "1"); } catch {}"#,
+        )]);
+
+        let mut gas = GasMeter::new(gas::EXECUTION_FLAT + 100);
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "gas.execute.ts",
+                &mut gas,
+            )
+            .await;
+        assert!(res.unwrap_err().is::<gas::OutOfGas>());
+        assert_eq!(gas.used(), gas.limit());
+        assert_eq!(store.lock().await.get("gas/a".into()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_loop_out_of_gas() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[
+            ("loop.execute.ts", "while (true) {}"),
+            (
+                "caught.execute.ts",
+                "while (true) { try { while (true) {} } catch {} }",
+            ),
+            (
+                "recursion.execute.ts",
+                "const f = (n) => (n ? f(n - 1) + f(n - 1) : 0); f(64);",
+            ),
+            ("eval.execute.ts", r#"eval("while (true) {}");"#),
+        ]);
+
+        for name in ["loop", "caught", "recursion"] {
+            // The same work is charged the same gas on every run.
+            let mut used = vec![];
+            for _ in 0..2 {
+                let mut gas = GasMeter::new(1_000_000);
+                let res = scripts
+                    .run(
+                        &store,
+                        RuntimeMode::Execute(Default::default()),
+                        &format!("{}.execute.ts", name),
+                        &mut gas,
+                    )
+                    .await;
+                assert!(res.unwrap_err().is::<gas::OutOfGas>(), "{}", name);
+                used.push(gas.used());
+            }
+            assert_eq!(used[0], used[1], "{}", name);
+        }
+
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "eval.execute.ts",
+                &mut GasMeter::new(1_000_000),
+            )
+            .await;
+        assert!(res.unwrap_err().to_string().contains("EvalError"));
+    }
+
+    #[tokio::test]
+    async fn test_runtime_timeout() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts =
+            TestScripts::new(&[("timeout.execute.ts", "while (true) {}")]).limits(Limits {
+                timeout: std::time::Duration::from_millis(100),
+                ..Default::default()
+            });
+
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "timeout.execute.ts",
+                &mut GasMeter::unlimited(),
+            )
+            .await;
+        assert!(res.unwrap_err().is::<TimedOut>());
+    }
+
+    #[tokio::test]
+    async fn test_runtime_heap_limit() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[(
+            "heap.execute.ts",
+            r#"await store.set("a", "1");
+            const chunks = []; while (true) { chunks.push(new Array(1 << 16).fill(0)); }"#,
+        )])
+        .limits(Limits {
+            max_heap_bytes: 32 << 20,
+            ..Default::default()
+        });
+
+        let mut gas = GasMeter::unlimited();
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "heap.execute.ts",
+                &mut gas,
+            )
+            .await;
+        assert!(res.unwrap_err().is::<HeapLimitExceeded>());
+        // The write is charged even though the run was terminated.
+        assert!(gas.used() >= gas::EXECUTION_FLAT + gas::WRITE_FLAT);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_deterministic_env() {
+        let scripts = TestScripts::new(&[(
+            "env.execute.ts",
+            r#"
+            class BlockDate extends Date {}
+            if (!(new BlockDate() instanceof BlockDate)) throw new Error("prototype lost");
+            await store.set("now", String(Date.now()));
+            await store.set("date", String(new BlockDate().getTime()));
+            await store.set("random", String(Math.random()));
+            await store.set("globals", [typeof Intl, typeof WeakRef, typeof Deno].join());
+            await store.set(
+                "constructor",
+                [new Date().constructor.now(), Date.prototype.constructor.now()].join(),
+            );
+            "#,
+        )]);
+
+        let run_in = |tx_index| {
+            let scripts = &scripts;
+            async move {
+                let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+                let block = runtime::BlockContext {
+                    height: 1,
+                    time_ms: 1_700_000_000_000,
+                    hash: vec![7; 32],
+                    tx_index,
+                };
+                scripts
+                    .run(
+                        &store,
+                        RuntimeMode::Execute(block),
+                        "env.execute.ts",
+                        &mut GasMeter::unlimited(),
+                    )
+                    .await
+                    .unwrap();
+                let store = store.lock().await;
+                let mut values = vec![];
+                for key in ["now", "date", "random", "globals", "constructor"] {
+                    let value = store.get(format!("env/{}", key).into()).await.unwrap();
+                    values.push(String::from_utf8(value.unwrap()).unwrap());
+                }
+                values
+            }
+        };
+
+        let first = run_in(0).await;
+        assert_eq!(first[0], "1700000000000");
+        assert_eq!(first[1], "1700000000000");
+        assert_eq!(first[3], "undefined,undefined,undefined");
+        assert_eq!(first[4], "1700000000000,1700000000000");
+        // Math.random only depends on the block hash and the tx index.
+        assert_eq!(run_in(0).await, first);
+        assert_ne!(run_in(1).await[2], first[2]);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_std_modules() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[(
+            "std.query.ts",
+            r#"
+            import { context } from "comet:context@1";
+            import { sha256 } from "comet:crypto";
+            import { encodeHex } from "comet:encoding";
+            import store from "comet:store";
+            context.respond({
+              hash: encodeHex(sha256("abc")),
+              aliased: store === globalThis.store && context === globalThis.context,
+            });
+            "#,
+        )]);
+
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Query(None),
+                "std.query.ts",
+                &mut GasMeter::unlimited(),
+            )
+            .await
+            .unwrap();
+        let runtime::RuntimeRunResult::Query(res, _) = res else {
+            panic!("unexpected result {}", res);
+        };
+        assert_eq!(
+            res["hash"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(res["aliased"], true);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_call() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[
+            (
+                "callee.execute.ts",
+                r#"
+                const { value } = context.getRequest();
+                await store.set("key", value);
+                context.emit({
+                  type: "callee",
+                  attributes: [{ key: "sender", value: context.getSender(), index: false }],
+                });
+                "#,
+            ),
+            (
+                "reader.query.ts",
+                r#"
+                const value = await store.namespace("callee").get("key");
+                context.respond({ value });
+                "#,
+            ),
+            (
+                "caller.execute.ts",
+                r#"
+                await context.call("callee", { value: "1" });
+                const { value } = await context.call("reader", {});
+                if (value !== "1") throw new Error(`read ${value}`);
+                for (const name of ["missing", "unregistered"]) {
+                  let called = true;
+                  try {
+                    await context.call(name, {});
+                  } catch {
+                    called = false;
+                  }
+                  if (called) throw new Error(`called ${name}`);
+                }
+                context.emit({ type: "caller", attributes: [] });
+                "#,
+            ),
+        ])
+        .grants([(
+            "reader".to_string(),
+            Permissions {
+                read: ["callee".to_string()].into(),
+                ..Default::default()
+            },
+        )]);
+
+        // On disk but not registered with the node.
+        std::fs::write(
+            scripts.path("unregistered.query.ts"),
+            "context.respond({});",
+        )
+        .unwrap();
+
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "caller.execute.ts",
+                &mut GasMeter::unlimited(),
+            )
+            .await
+            .unwrap();
+        let runtime::RuntimeRunResult::Execute(events) = res else {
+            panic!("unexpected result {}", res);
+        };
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].attributes[0].value, "caller");
+        assert_eq!(
+            store.lock().await.get("callee/key".into()).await.unwrap(),
+            Some("1".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_call_terminated() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[
+            (
+                "hog.execute.ts",
+                "const chunks = []; while (true) { chunks.push(new Array(1 << 16).fill(0)); }",
+            ),
+            (
+                "caller.execute.ts",
+                r#"
+                try {
+                  await context.call("hog", {});
+                } catch {}
+                await store.set("after", "1");
+                "#,
+            ),
+        ])
+        .limits(Limits {
+            max_heap_bytes: 32 << 20,
+            ..Default::default()
+        });
+
+        let res = scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(Default::default()),
+                "caller.execute.ts",
+                &mut GasMeter::unlimited(),
+            )
+            .await;
+        assert!(res.unwrap_err().is::<HeapLimitExceeded>());
+        assert_eq!(
+            store.lock().await.get("caller/after".into()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_call_query_sees_block() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let scripts = TestScripts::new(&[
+            ("clock.query.ts", "context.respond({ now: Date.now() });"),
+            (
+                "caller.execute.ts",
+                r#"
+                const { now } = await context.call("clock", {});
+                await store.set("now", String(now));
+                "#,
+            ),
+        ]);
+
+        let block = runtime::BlockContext {
+            time_ms: 1_700_000_000_000,
+            ..Default::default()
+        };
+        scripts
+            .run(
+                &store,
+                RuntimeMode::Execute(block),
+                "caller.execute.ts",
+                &mut GasMeter::unlimited(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store.lock().await.get("caller/now".into()).await.unwrap(),
+            Some("1700000000000".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_sandbox() {
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+
+        for import in ["https://deno.land/std/mod.ts", "../outside.ts"] {
+            let scripts =
+                TestScripts::new(&[("sandbox.execute.ts", &format!("import {:?};", import))]);
+            // Right next to the scripts directory, but not in it.
+            std::fs::write(scripts.root.join("outside.ts"), "").unwrap();
+
+            let res = scripts
+                .run(
+                    &store,
+                    RuntimeMode::Execute(Default::default()),
+                    "sandbox.execute.ts",
+                    &mut GasMeter::unlimited(),
+                )
+                .await;
+            assert!(res.is_err(), "{} was imported", import);
+        }
+    }
+
+    #[test]
+    fn test_precompile() {
+        let scripts = TestScripts::new(&[
+            ("kv-set.execute.ts", r#"await store.set("key", "value");"#),
+            ("broken.execute.ts", "const = 1;"),
+        ]);
+        let path = scripts.path("broken.execute.ts");
+
+        runtime::precompile(
+            &[scripts.path("kv-set.execute.ts")],
+            Default::default(),
+            &scripts.options(),
+        )
+        .unwrap();
+        let err = runtime::precompile(&[path.clone()], Default::default(), &scripts.options())
+            .unwrap_err();
+        assert!(err.to_string().contains(&path));
+    }
+
+    /// Compares the per-transaction time of runs on a runtime created for
+    /// each, with nothing cached, on a pool warmed before every run, as when
+    /// transactions trickle in, and on a pool warmed only between bursts of
+    /// more runs than it holds, as when a block holds many transactions. Run
+    /// with `cargo test --release bench_runtime_pool -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_runtime_pool() {
+        use std::time::{Duration, Instant};
+
+        const RUNS: u32 = 96;
+        const POOL_SIZE: usize = 4;
+        const BURST: u32 = 16;
+
+        let scripts = TestScripts::new(&[(
+            "kv-set.execute.ts",
+            r#"
+            const { key, value } = context.getRequest();
+            await store.set(key, value);
+            context.emit({ type: "kv-set", attributes: [{ key, value, index: false }] });
+            "#,
+        )]);
+        let path = scripts.path("kv-set.execute.ts");
+
+        /// Runs the `i`th transaction, returning how long it took.
+        async fn tx(
+            pool: &mut RuntimePool,
+            store: &Arc<Mutex<dyn Store>>,
+            path: &str,
+            i: u32,
+        ) -> Duration {
+            let start = Instant::now();
+            run(
+                Arc::clone(store),
+                RuntimeMode::Execute(Default::default()),
+                "<sender>",
+                json!({"key": format!("key-{}", i), "value": "value"}),
+                path,
+                Default::default(),
+                Default::default(),
+                &mut GasMeter::unlimited(),
+                pool,
+            )
+            .await
+            .unwrap();
+            start.elapsed()
+        }
+
+        let store: Arc<Mutex<dyn Store>> = Arc::new(Mutex::new(MemoryStore::new()));
+        let options = PoolOptions {
+            size: POOL_SIZE,
+            ..scripts.options()
+        };
+
+        let mut cold = Duration::ZERO;
+        for i in 0..RUNS {
+            let mut pool = RuntimePool::new(Default::default(), options.clone());
+            cold += tx(&mut pool, &store, &path, i).await;
+        }
+
+        let mut pool = RuntimePool::new(Default::default(), options.clone());
+        let mut warm = Duration::ZERO;
+        for i in 0..RUNS {
+            pool.warm_one();
+            warm += tx(&mut pool, &store, &path, i).await;
+        }
+
+        // The first `POOL_SIZE` runs of a burst take warm runtimes, the
+        // others create theirs.
+        let (mut pooled, mut overflow) = (Duration::ZERO, Duration::ZERO);
+        for i in 0..RUNS {
+            if i % BURST == 0 {
+                while pool.warm_one() {}
+            }
+            let elapsed = tx(&mut pool, &store, &path, i).await;
+            if i % BURST < POOL_SIZE as u32 {
+                pooled += elapsed;
+            } else {
+                overflow += elapsed;
+            }
+        }
+
+        let bursts = RUNS / BURST;
+        let overflow_runs = RUNS - bursts * POOL_SIZE as u32;
+        println!("per-tx time, cold: {:?}", cold / RUNS);
+        println!("per-tx time, warm pool and module cache: {:?}", warm / RUNS);
+        println!(
+            "per-tx time, bursts of {} on a pool of {}: {:?} overall, {:?} past the pool",
+            BURST,
+            POOL_SIZE,
+            (pooled + overflow) / RUNS,
+            overflow / overflow_runs,
+        );
+    }
 }
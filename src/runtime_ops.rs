@@ -1,43 +1,145 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, sync::Arc};
 
-use deno_core::{error::AnyError, op2, OpState};
+use deno_core::{error::AnyError, op2, JsBuffer, OpState, ToJsBuffer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tendermint_proto::abci::Event;
 
-use crate::runtime::OpStateContext;
+use crate::{
+    gas,
+    genesis::{params_key, CHAIN_ID_KEY},
+    runtime::{
+        self, HeapLimitExceeded, OpStateContext, RuntimeMode, RuntimeRunResult, TimedOut,
+        MAX_CALL_DEPTH,
+    },
+    store::ListOptions,
+};
 
 #[op2(async)]
-#[string]
+#[serde]
 #[allow(clippy::await_holding_refcell_ref)]
 pub(crate) async fn op_kv_set(
     state: Rc<RefCell<OpState>>,
-    #[string] key: String,
-    #[string] value: String,
-) -> Result<String, AnyError> {
-    let state = state.borrow_mut();
-    let ctx: &OpStateContext = state.borrow();
+    #[buffer] key: JsBuffer,
+    #[buffer] value: JsBuffer,
+    #[serde] namespace: Option<String>,
+) -> Result<ToJsBuffer, AnyError> {
+    let mut state = state.borrow_mut();
+    let ctx: &mut OpStateContext = state.borrow_mut();
 
     ctx.mode.assert_execute()?;
+    let key = ctx.namespaced_key(namespace.as_deref(), &key, true)?;
+    ctx.gas.consume(
+        gas::WRITE_FLAT + gas::WRITE_PER_BYTE * (key.len() + value.len()) as u64,
+        "op_kv_set",
+    )?;
 
     let mut store = ctx.store.lock().await;
-    let resp = store.set(key, value.clone()).await?;
+    let resp = store.set(key, value.to_vec()).await?;
 
-    Ok(resp.unwrap_or(value))
+    Ok(resp.unwrap_or_else(|| value.to_vec()).into())
 }
 
 #[op2(async)]
-#[string]
+#[serde]
+#[allow(clippy::await_holding_refcell_ref)]
+pub(crate) async fn op_kv_delete(
+    state: Rc<RefCell<OpState>>,
+    #[buffer] key: JsBuffer,
+    #[serde] namespace: Option<String>,
+) -> Result<Option<ToJsBuffer>, AnyError> {
+    let mut state = state.borrow_mut();
+    let ctx: &mut OpStateContext = state.borrow_mut();
+
+    ctx.mode.assert_execute()?;
+    let key = ctx.namespaced_key(namespace.as_deref(), &key, true)?;
+    ctx.gas.consume(gas::DELETE_FLAT, "op_kv_delete")?;
+
+    let mut store = ctx.store.lock().await;
+    let resp = store.delete(key).await?;
+
+    Ok(resp.map(Into::into))
+}
+
+#[op2(async)]
+#[serde]
 #[allow(clippy::await_holding_refcell_ref)]
 pub(crate) async fn op_kv_get(
     state: Rc<RefCell<OpState>>,
-    #[string] key: String,
-) -> Result<String, AnyError> {
-    let state = state.borrow_mut();
-    let ctx: &OpStateContext = state.borrow();
+    #[buffer] key: JsBuffer,
+    #[serde] namespace: Option<String>,
+) -> Result<ToJsBuffer, AnyError> {
+    let mut state = state.borrow_mut();
+    let ctx: &mut OpStateContext = state.borrow_mut();
+
+    let key = ctx.namespaced_key(namespace.as_deref(), &key, false)?;
+    ctx.reads.insert(key.clone());
+
+    let res = ctx.store.lock().await.get(key.clone()).await?;
+    let len = key.len() + res.as_ref().map_or(0, Vec::len);
+    ctx.gas.consume(
+        gas::READ_FLAT + gas::READ_PER_BYTE * len as u64,
+        "op_kv_get",
+    )?;
+
+    res.map(Into::into).ok_or(AnyError::msg("key not found"))
+}
+
+/// Most pairs a single `op_kv_list` call reads, so that a call cannot load an
+/// unbounded range before its gas is charged. `std/v1/store.js` pages below it.
+const MAX_LIST_LIMIT: usize = 1_000;
+
+#[derive(Deserialize)]
+pub(crate) struct ListArgs {
+    namespace: Option<String>,
+    prefix: Option<JsBuffer>,
+    start: Option<JsBuffer>,
+    end: Option<JsBuffer>,
+    #[serde(default)]
+    reverse: bool,
+    limit: Option<usize>,
+}
+
+impl ListArgs {
+    /// Store options selecting the same keys inside the namespace `ns_prefix`,
+    /// at most `MAX_LIST_LIMIT` of them.
+    fn into_options(self, ns_prefix: &[u8]) -> ListOptions {
+        let namespaced = |key: JsBuffer| [ns_prefix, &key].concat();
+        ListOptions {
+            prefix: Some([ns_prefix, self.prefix.as_deref().unwrap_or_default()].concat()),
+            start: self.start.map(namespaced),
+            end: self.end.map(namespaced),
+            reverse: self.reverse,
+            limit: Some(self.limit.unwrap_or(MAX_LIST_LIMIT).min(MAX_LIST_LIMIT)),
+        }
+    }
+}
+
+#[op2(async)]
+#[serde]
+#[allow(clippy::await_holding_refcell_ref)]
+pub(crate) async fn op_kv_list(
+    state: Rc<RefCell<OpState>>,
+    #[serde] args: ListArgs,
+) -> Result<Vec<(ToJsBuffer, ToJsBuffer)>, AnyError> {
+    let mut state = state.borrow_mut();
+    let ctx: &mut OpStateContext = state.borrow_mut();
+
+    let ns_prefix = ctx.namespace_prefix(args.namespace.as_deref(), false)?;
+    let opts = args.into_options(&ns_prefix);
 
-    let store = ctx.store.lock().await;
-    let res = store.get(key).await?;
+    let pairs = ctx.store.lock().await.list(&opts).await?;
+    let len: usize = pairs.iter().map(|(k, v)| k.len() + v.len()).sum();
+    ctx.gas.consume(
+        gas::READ_FLAT + gas::ITER_NEXT_FLAT * pairs.len() as u64 + gas::READ_PER_BYTE * len as u64,
+        "op_kv_list",
+    )?;
+    ctx.reads.extend(pairs.iter().map(|(k, _)| k.clone()));
 
-    res.ok_or(AnyError::msg("key not found"))
+    Ok(pairs
+        .into_iter()
+        .map(|(k, v)| (k[ns_prefix.len()..].to_vec().into(), v.into()))
+        .collect())
 }
 
 #[op2]
@@ -47,6 +149,17 @@ pub(crate) fn op_ctx_emit(
 ) -> Result<(), AnyError> {
     ctx.mode.assert_execute()?;
 
+    let len: usize = event.r#type.len()
+        + event
+            .attributes
+            .iter()
+            .map(|a| a.key.len() + a.value.len())
+            .sum::<usize>();
+    ctx.gas.consume(
+        gas::EVENT_FLAT + gas::EVENT_PER_BYTE * len as u64,
+        "op_ctx_emit",
+    )?;
+
     ctx.events.push(event);
 
     Ok(())
@@ -81,3 +194,159 @@ pub(crate) fn op_ctx_get_request(
 ) -> Result<serde_json::Value, AnyError> {
     Ok(ctx.request.clone())
 }
+
+#[op2(async)]
+#[string]
+#[allow(clippy::await_holding_refcell_ref)]
+pub(crate) async fn op_ctx_get_chain_id(state: Rc<RefCell<OpState>>) -> Result<String, AnyError> {
+    let mut state = state.borrow_mut();
+    let ctx: &mut OpStateContext = state.borrow_mut();
+
+    ctx.reads.insert(CHAIN_ID_KEY.to_vec());
+    let chain_id = ctx.store.lock().await.get(CHAIN_ID_KEY.to_vec()).await?;
+    ctx.gas.consume(gas::READ_FLAT, "op_ctx_get_chain_id")?;
+
+    Ok(String::from_utf8(chain_id.unwrap_or_default())?)
+}
+
+#[op2(async)]
+#[serde]
+#[allow(clippy::await_holding_refcell_ref)]
+pub(crate) async fn op_ctx_get_params(
+    state: Rc<RefCell<OpState>>,
+) -> Result<serde_json::Value, AnyError> {
+    let mut state = state.borrow_mut();
+    let ctx: &mut OpStateContext = state.borrow_mut();
+
+    let key = params_key(&ctx.namespace);
+    ctx.reads.insert(key.clone());
+    ctx.gas.consume(gas::READ_FLAT, "op_ctx_get_params")?;
+    match ctx.store.lock().await.get(key).await? {
+        Some(params) => Ok(serde_json::from_slice(&params)?),
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct BlockInfo {
+    height: i64,
+    time: i64,
+    /// Seed of `Math.random`, derived from the block hash and tx index.
+    seed: [u32; 4],
+}
+
+#[op2]
+#[serde]
+pub(crate) fn op_ctx_get_block(
+    #[state] ctx: &OpStateContext,
+) -> Result<Option<BlockInfo>, AnyError> {
+    // A query called from a transaction runs in the transaction's block.
+    let (RuntimeMode::Execute(block) | RuntimeMode::Query(Some(block))) = &ctx.mode else {
+        return Ok(None);
+    };
+
+    let digest = Sha256::new()
+        .chain_update(&block.hash)
+        .chain_update(block.tx_index.to_be_bytes())
+        .finalize();
+    let mut seed = [0; 4];
+    for (word, bytes) in seed.iter_mut().zip(digest.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into()?);
+    }
+
+    Ok(Some(BlockInfo {
+        height: block.height,
+        time: block.time_ms,
+        seed,
+    }))
+}
+
+/// Runs the script `name` within the current run. The callee sees the caller
+/// as its sender, uses the caller's gas, and reads and writes through the
+/// caller's store, so that its writes and events become the caller's once it
+/// completes, or are dropped along with its error. A callee terminated for
+/// exceeding its limits fails the caller's run too, see `run`.
+#[op2(async)]
+#[serde]
+pub(crate) async fn op_ctx_call(
+    state: Rc<RefCell<OpState>>,
+    #[string] name: String,
+    #[serde] request: serde_json::Value,
+) -> Result<serde_json::Value, AnyError> {
+    let (store, mode, file_path, sender, grants, scripts, mut gas, mut pool, depth) = {
+        let state = state.borrow();
+        let ctx = state.borrow::<OpStateContext>();
+        if ctx.depth >= MAX_CALL_DEPTH {
+            return Err(AnyError::msg(format!(
+                "calls nested deeper than {}",
+                MAX_CALL_DEPTH
+            )));
+        }
+        let (file_path, mode) = ctx.callee(&name)?;
+        (
+            Arc::clone(&ctx.store),
+            mode,
+            file_path,
+            ctx.namespace.clone(),
+            Arc::clone(&ctx.grants),
+            Arc::clone(&ctx.scripts),
+            ctx.gas.clone(),
+            ctx.pool.on_demand(),
+            ctx.depth + 1,
+        )
+    };
+
+    let result = runtime::run_nested(
+        store, mode, &sender, request, &file_path, grants, scripts, &mut gas, &mut pool, depth,
+    )
+    .await;
+
+    let mut state = state.borrow_mut();
+    let ctx = state.borrow_mut::<OpStateContext>();
+    ctx.gas = gas;
+    let result = match result {
+        Err(err) if err.is::<HeapLimitExceeded>() || err.is::<TimedOut>() => {
+            let message = err.to_string();
+            ctx.terminated.get_or_insert(err);
+            return Err(AnyError::msg(message));
+        }
+        result => result?,
+    };
+    match result {
+        RuntimeRunResult::Query(response, reads) => {
+            ctx.reads.extend(reads);
+            Ok(response)
+        }
+        RuntimeRunResult::Execute(events) => {
+            ctx.events.extend(events);
+            Ok(serde_json::Value::Null)
+        }
+    }
+}
+
+#[op2]
+#[serde]
+pub(crate) fn op_crypto_sha256(
+    #[state] ctx: &mut OpStateContext,
+    #[buffer] data: JsBuffer,
+) -> Result<ToJsBuffer, AnyError> {
+    ctx.gas.consume(
+        gas::HASH_FLAT + gas::HASH_PER_BYTE * data.len() as u64,
+        "op_crypto_sha256",
+    )?;
+
+    Ok(Sha256::digest(&data).to_vec().into())
+}
+
+/// Charges `count` ticks of the instrumented code, see `meter`. Running out of
+/// gas terminates the run right away, as a script could otherwise catch the
+/// error and keep looping without ever calling another op.
+#[op2(fast)]
+pub(crate) fn op_gas_tick(#[state] ctx: &mut OpStateContext, count: u32) -> Result<(), AnyError> {
+    if let Err(err) = ctx.gas.consume(gas::TICK * count as u64, "op_gas_tick") {
+        ctx.isolate.terminate_execution();
+        return Err(err.into());
+    }
+
+    Ok(())
+}
@@ -1,8 +1,55 @@
-use std::{collections::HashMap, fs::read_dir};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
+
+use deno_ast::MediaType;
+use serde::Deserialize;
+
+use crate::lint::{lint, LintOptions};
 
 const ALLOWED_SCRIPTS: [&str; 2] = ["query", "execute"];
+const PERMISSIONS_FILE: &str = "permissions.json";
+
+/// Namespaces a script may access besides its own.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Permissions {
+    /// Namespaces the script may read from.
+    #[serde(default)]
+    pub read: HashSet<String>,
+    /// Namespaces the script may read from and write to.
+    #[serde(default)]
+    pub write: HashSet<String>,
+}
+
+impl Permissions {
+    pub fn can_read(&self, namespace: &str) -> bool {
+        self.read.contains(namespace) || self.can_write(namespace)
+    }
+
+    pub fn can_write(&self, namespace: &str) -> bool {
+        self.write.contains(namespace)
+    }
+}
+
+/// Name of the script at `path`, which is also the namespace its keys live in.
+pub fn script_name(path: &str) -> &str {
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    file_name.split('.').next().unwrap_or(file_name)
+}
+
+/// Lists the query and execute scripts of `scripts_dir` by kind, after checking
+/// every script of the directory for nondeterministic constructs.
+pub fn load_scripts(
+    scripts_dir: &str,
+    lint_options: LintOptions,
+) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    lint_scripts(scripts_dir, lint_options)?;
 
-pub fn load_scripts(scripts_dir: &str) -> anyhow::Result<HashMap<String, Vec<String>>> {
     let resp = read_dir(scripts_dir)?
         .flat_map(|entry| {
             let entry = entry.unwrap();
@@ -37,3 +84,90 @@ pub fn load_scripts(scripts_dir: &str) -> anyhow::Result<HashMap<String, Vec<Str
 
     Ok(resp)
 }
+
+/// Fails with every diagnostic of every script if any uses a banned construct.
+/// Modules in subdirectories, which scripts may import, are checked as well.
+fn lint_scripts(scripts_dir: &str, options: LintOptions) -> anyhow::Result<()> {
+    fn visit(dir: &Path, paths: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                visit(&path, paths)?;
+            } else if matches!(
+                MediaType::from_path(&path),
+                // Every media type but JSON the module loader accepts.
+                MediaType::JavaScript
+                    | MediaType::Mjs
+                    | MediaType::Cjs
+                    | MediaType::Jsx
+                    | MediaType::TypeScript
+                    | MediaType::Mts
+                    | MediaType::Cts
+                    | MediaType::Dts
+                    | MediaType::Dmts
+                    | MediaType::Dcts
+                    | MediaType::Tsx
+            ) {
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut paths = vec![];
+    visit(Path::new(scripts_dir), &mut paths)?;
+    paths.sort();
+
+    let mut diagnostics = vec![];
+    for path in paths {
+        diagnostics.extend(lint(&path, options)?);
+    }
+    if !diagnostics.is_empty() {
+        let report = diagnostics
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        anyhow::bail!("scripts use nondeterministic constructs:\n{}", report);
+    }
+
+    Ok(())
+}
+
+/// Loads the cross-namespace grants declared in `permissions.json`, keyed by
+/// script name. A missing file grants nothing.
+pub fn load_permissions(scripts_dir: &str) -> anyhow::Result<HashMap<String, Permissions>> {
+    let path = Path::new(scripts_dir).join(PERMISSIONS_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_lint_imported_modules() {
+        let dir = TempDir::new("script");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("timer.query.ts"),
+            "import { later } from \"./lib/timer.mts\";\nexport default later;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lib/timer.mts"),
+            "export const later = (f) => setTimeout(f, 0);\n",
+        )
+        .unwrap();
+
+        let err = load_scripts(dir.to_str().unwrap(), LintOptions::default())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("timer.mts"), "{}", err);
+    }
+}
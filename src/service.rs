@@ -1,35 +1,72 @@
-//! In-memory key/value store ABCI application.
+//! Deno-scripted key/value store ABCI application.
 
 use std::{
     collections::HashMap,
-    sync::mpsc::{channel, Receiver, Sender},
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
 };
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use tendermint_proto::{
     abci::ExecTxResult,
+    google::protobuf::Timestamp,
     v0_38::abci::{
-        RequestCheckTx, RequestFinalizeBlock, RequestInfo, RequestQuery, ResponseCheckTx,
-        ResponseCommit, ResponseFinalizeBlock, ResponseInfo, ResponseQuery,
+        response_apply_snapshot_chunk, response_offer_snapshot, RequestApplySnapshotChunk,
+        RequestCheckTx, RequestFinalizeBlock, RequestInfo, RequestInitChain,
+        RequestLoadSnapshotChunk, RequestOfferSnapshot, RequestQuery, ResponseApplySnapshotChunk,
+        ResponseCheckTx, ResponseCommit, ResponseFinalizeBlock, ResponseInfo, ResponseInitChain,
+        ResponseListSnapshots, ResponseLoadSnapshotChunk, ResponseOfferSnapshot, ResponseQuery,
     },
 };
-use tracing::{debug, info};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
 
 use tendermint_abci::{Application, Error};
 
 use crate::{
+    gas::{DEFAULT_TX_GAS_LIMIT, MAX_TX_GAS_LIMIT},
+    genesis::INIT_SCRIPT,
+    lint::LintOptions,
+    pruning::Pruning,
     runner::{Runner, RunnerCommand},
-    script::load_scripts,
+    runtime::{self, BlockContext, Limits, PoolOptions},
+    script::{load_permissions, load_scripts},
+    snapshot::Snapshots,
+    store::Store,
 };
 
-pub const MAX_VARINT_LENGTH: usize = 16;
+/// Query path answering with the raw value stored under `RequestQuery.data`
+/// instead of running a query script. Keys are namespaced by the script that
+/// owns them, e.g. `kv-set/name`.
+pub const STORE_QUERY_PATH: &str = "/store";
 
 #[derive(Serialize, Deserialize)]
 struct Tx {
     pub path: String,
     pub sender: String,
     pub request: serde_json::Value,
+    /// Maximum gas the transaction may use, `DEFAULT_TX_GAS_LIMIT` if unset and
+    /// at most `MAX_TX_GAS_LIMIT`.
+    #[serde(default)]
+    pub gas_limit: Option<u64>,
+}
+
+impl Tx {
+    fn gas_limit(&self) -> anyhow::Result<u64> {
+        let gas_limit = self.gas_limit.unwrap_or(DEFAULT_TX_GAS_LIMIT);
+        if gas_limit > MAX_TX_GAS_LIMIT {
+            anyhow::bail!(
+                "gas limit {} exceeds the maximum of {}",
+                gas_limit,
+                MAX_TX_GAS_LIMIT
+            );
+        }
+        Ok(gas_limit)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -40,21 +77,59 @@ pub struct DenoKVService {
 }
 
 impl DenoKVService {
-    /// Constructor.
-    pub fn new(scripts_dir: &str) -> (Self, Runner) {
+    /// Constructor, failing if any script does not pass the lint or does not
+    /// compile.
+    pub fn new(
+        scripts_dir: &str,
+        store: Arc<Mutex<dyn Store>>,
+        pruning: Pruning,
+        snapshots: Snapshots,
+        limits: Limits,
+        lint_options: LintOptions,
+        pool_options: PoolOptions,
+    ) -> anyhow::Result<(Self, Runner)> {
         let (cmd_tx, cmd_rx) = channel();
-        let scripts = load_scripts(scripts_dir).unwrap();
-        (
+        let scripts = load_scripts(scripts_dir, lint_options)?;
+        let permissions = load_permissions(scripts_dir)?;
+
+        let mut paths = scripts
+            .iter()
+            .flat_map(|(kind, names)| {
+                names
+                    .iter()
+                    .map(move |name| format!("{}/{}.{}.ts", scripts_dir, name, kind))
+            })
+            .collect::<Vec<_>>();
+        if Path::new(scripts_dir).join(INIT_SCRIPT).exists() {
+            paths.push(format!("{}/{}", scripts_dir, INIT_SCRIPT));
+        }
+        paths.sort();
+        let cache = runtime::precompile(&paths, limits, &pool_options)?;
+        Ok((
             Self {
                 cmd_tx,
                 scripts_dir: scripts_dir.to_string(),
-                scripts,
+                scripts: scripts.clone(),
             },
-            Runner::new(cmd_rx),
-        )
+            Runner::new(
+                cmd_rx,
+                store,
+                permissions,
+                scripts,
+                pruning,
+                snapshots,
+                limits,
+                pool_options,
+                cache,
+            ),
+        ))
     }
 
     fn query(&self, req: RequestQuery) -> ResponseQuery {
+        if req.path == STORE_QUERY_PATH {
+            return self.query_key(req);
+        }
+
         if !self
             .scripts
             .get("query")
@@ -74,6 +149,8 @@ impl DenoKVService {
             RunnerCommand::Query {
                 path: format!("{}/{}.query.ts", self.scripts_dir, req.path),
                 request: req.data,
+                height: req.height,
+                prove: req.prove,
                 result_tx,
             },
         )
@@ -91,7 +168,32 @@ impl DenoKVService {
         }
     }
 
-    fn execute(&self, tx: Bytes) -> ExecTxResult {
+    fn query_key(&self, req: RequestQuery) -> ResponseQuery {
+        let (result_tx, result_rx) = channel();
+        channel_send(
+            &self.cmd_tx,
+            RunnerCommand::QueryKey {
+                key: req.data,
+                height: req.height,
+                prove: req.prove,
+                result_tx,
+            },
+        )
+        .unwrap();
+
+        let resp = channel_recv(&result_rx).unwrap();
+
+        match resp {
+            Ok(v) => v,
+            Err(err) => ResponseQuery {
+                code: 1,
+                log: err.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn execute(&self, tx: Bytes, block: BlockContext) -> ExecTxResult {
         let tx: Tx = serde_json::from_slice(&tx).unwrap();
 
         if !self
@@ -106,12 +208,24 @@ impl DenoKVService {
                 ..Default::default()
             };
         }
+        let gas_limit = match tx.gas_limit() {
+            Ok(gas_limit) => gas_limit,
+            Err(err) => {
+                return ExecTxResult {
+                    code: 1,
+                    log: err.to_string(),
+                    ..Default::default()
+                }
+            }
+        };
 
         let (result_tx, result_rx) = channel();
         channel_send(
             &self.cmd_tx,
             RunnerCommand::Execute {
                 path: format!("{}/{}.execute.ts", self.scripts_dir, tx.path),
+                gas_limit,
+                block,
                 sender: tx.sender,
                 request: tx.request,
                 result_tx,
@@ -152,26 +266,104 @@ impl Application for DenoKVService {
         }
     }
 
+    fn init_chain(&self, request: RequestInitChain) -> ResponseInitChain {
+        info!(
+            "Got init chain request. Chain id: {}; Validators: {}",
+            request.chain_id,
+            request.validators.len()
+        );
+
+        let init_script = Path::new(&self.scripts_dir).join(INIT_SCRIPT);
+        let (result_tx, result_rx) = channel();
+        channel_send(
+            &self.cmd_tx,
+            RunnerCommand::InitChain {
+                chain_id: request.chain_id,
+                initial_height: request.initial_height,
+                app_state: request.app_state_bytes,
+                init_script: init_script
+                    .exists()
+                    .then(|| format!("{}/{}", self.scripts_dir, INIT_SCRIPT)),
+                block: BlockContext {
+                    height: request.initial_height,
+                    time_ms: timestamp_ms(request.time.as_ref()),
+                    ..Default::default()
+                },
+                result_tx,
+            },
+        )
+        .unwrap();
+
+        // A node that cannot apply the genesis must not join the chain.
+        let app_hash = channel_recv(&result_rx)
+            .unwrap()
+            .unwrap_or_else(|err| panic!("failed to apply genesis: {:?}", err));
+
+        ResponseInitChain {
+            app_hash: app_hash.into(),
+            ..Default::default()
+        }
+    }
+
     fn query(&self, request: RequestQuery) -> ResponseQuery {
         self.query(request)
     }
 
-    fn check_tx(&self, _request: RequestCheckTx) -> ResponseCheckTx {
+    fn check_tx(&self, request: RequestCheckTx) -> ResponseCheckTx {
+        let tx: Tx = match serde_json::from_slice(&request.tx) {
+            Ok(tx) => tx,
+            Err(err) => {
+                return ResponseCheckTx {
+                    code: 1,
+                    log: format!("invalid tx: {}", err),
+                    ..Default::default()
+                }
+            }
+        };
+
+        let gas_limit = tx
+            .gas_limit()
+            .and_then(|limit| i64::try_from(limit).map_err(anyhow::Error::from));
+        let gas_wanted = match gas_limit {
+            Ok(gas_wanted) => gas_wanted,
+            Err(err) => {
+                return ResponseCheckTx {
+                    code: 1,
+                    log: format!("invalid tx: {}", err),
+                    ..Default::default()
+                }
+            }
+        };
+
         ResponseCheckTx {
-            gas_wanted: 1,
+            gas_wanted,
             gas_used: 0,
             ..Default::default()
         }
     }
 
     fn finalize_block(&self, request: RequestFinalizeBlock) -> ResponseFinalizeBlock {
+        let time_ms = timestamp_ms(request.time.as_ref());
         let mut tx_results = vec![];
-        for tx in request.txs {
-            tx_results.push(self.execute(tx));
+        for (tx_index, tx) in request.txs.into_iter().enumerate() {
+            let block = BlockContext {
+                height: request.height,
+                time_ms,
+                hash: request.hash.to_vec(),
+                tx_index: tx_index as u32,
+            };
+            tx_results.push(self.execute(tx, block));
         }
 
+        let (result_tx, result_rx) = channel();
+        channel_send(&self.cmd_tx, RunnerCommand::FinalizeBlock { result_tx }).unwrap();
+        let app_hash = channel_recv(&result_rx)
+            .unwrap()
+            .unwrap_or_else(|err| panic!("failed to finalize block: {:?}", err));
+
         ResponseFinalizeBlock {
             tx_results,
+            app_hash: app_hash.into(),
             ..Default::default()
         }
     }
@@ -179,12 +371,102 @@ impl Application for DenoKVService {
     fn commit(&self) -> ResponseCommit {
         let (result_tx, result_rx) = channel();
         channel_send(&self.cmd_tx, RunnerCommand::Commit { result_tx }).unwrap();
-        let (height, _) = channel_recv(&result_rx).unwrap();
+        let (height, retain_height) = channel_recv(&result_rx).unwrap();
         info!("Committed height {}", height);
-        ResponseCommit {
-            retain_height: height - 1,
+        ResponseCommit { retain_height }
+    }
+
+    fn list_snapshots(&self) -> ResponseListSnapshots {
+        let (result_tx, result_rx) = channel();
+        channel_send(&self.cmd_tx, RunnerCommand::ListSnapshots { result_tx }).unwrap();
+
+        match channel_recv(&result_rx).unwrap() {
+            Ok(snapshots) => ResponseListSnapshots { snapshots },
+            Err(err) => {
+                error!("Failed to list snapshots: {:?}", err);
+                ResponseListSnapshots::default()
+            }
+        }
+    }
+
+    fn offer_snapshot(&self, request: RequestOfferSnapshot) -> ResponseOfferSnapshot {
+        let Some(snapshot) = request.snapshot else {
+            return ResponseOfferSnapshot {
+                result: response_offer_snapshot::Result::Reject.into(),
+            };
+        };
+
+        let (result_tx, result_rx) = channel();
+        channel_send(
+            &self.cmd_tx,
+            RunnerCommand::OfferSnapshot {
+                snapshot,
+                app_hash: request.app_hash,
+                result_tx,
+            },
+        )
+        .unwrap();
+
+        ResponseOfferSnapshot {
+            result: channel_recv(&result_rx).unwrap().into(),
         }
     }
+
+    fn load_snapshot_chunk(&self, request: RequestLoadSnapshotChunk) -> ResponseLoadSnapshotChunk {
+        let (result_tx, result_rx) = channel();
+        channel_send(
+            &self.cmd_tx,
+            RunnerCommand::LoadSnapshotChunk {
+                height: request.height,
+                format: request.format,
+                chunk: request.chunk,
+                result_tx,
+            },
+        )
+        .unwrap();
+
+        match channel_recv(&result_rx).unwrap() {
+            Ok(chunk) => ResponseLoadSnapshotChunk {
+                chunk: chunk.into(),
+            },
+            Err(err) => {
+                error!("Failed to load snapshot chunk: {:?}", err);
+                ResponseLoadSnapshotChunk::default()
+            }
+        }
+    }
+
+    fn apply_snapshot_chunk(
+        &self,
+        request: RequestApplySnapshotChunk,
+    ) -> ResponseApplySnapshotChunk {
+        let (result_tx, result_rx) = channel();
+        channel_send(
+            &self.cmd_tx,
+            RunnerCommand::ApplySnapshotChunk {
+                index: request.index,
+                chunk: request.chunk,
+                sender: request.sender,
+                result_tx,
+            },
+        )
+        .unwrap();
+
+        match channel_recv(&result_rx).unwrap() {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Failed to apply snapshot chunk: {:?}", err);
+                ResponseApplySnapshotChunk {
+                    result: response_apply_snapshot_chunk::Result::Abort.into(),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+}
+
+fn timestamp_ms(time: Option<&Timestamp>) -> i64 {
+    time.map_or(0, |t| t.seconds * 1000 + i64::from(t.nanos) / 1_000_000)
 }
 
 fn channel_send<T>(tx: &Sender<T>, value: T) -> Result<(), Error> {
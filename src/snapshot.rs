@@ -0,0 +1,398 @@
+//! State sync snapshots of the store and the script set.
+//!
+//! A snapshot holds every key/value pair committed at its height along with
+//! the content of every file under the scripts directory, encoded as a
+//! `SnapshotPayload` and split into chunks of `CHUNK_SIZE` bytes. The snapshot
+//! metadata lists the hash of every chunk, so a corrupted chunk is refetched
+//! as soon as it arrives, and the restored pairs are checked against the
+//! trusted app hash before anything is written to the store.
+//!
+//! Scripts are not covered by the app hash, so a restore does not install the
+//! ones it receives: it refuses snapshots taken with a different script set,
+//! since executing the following blocks with other scripts would diverge.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tendermint_proto::abci::{response_offer_snapshot, Snapshot};
+
+use crate::merkle;
+
+/// Version of the snapshot encoding, bumped on incompatible changes.
+pub const FORMAT: u32 = 1;
+const CHUNK_SIZE: usize = 4 << 20;
+/// Most chunks an offered snapshot may have, i.e. 64 GiB of state, so a peer
+/// cannot make `offer` allocate for an arbitrary count.
+const MAX_CHUNKS: u32 = 16 << 10;
+/// File holding the encoded `Snapshot` in the directory of a snapshot.
+const DESCRIPTOR_FILE: &str = "snapshot";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Pair {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScriptFile {
+    /// Path relative to the scripts directory.
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub content: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SnapshotPayload {
+    #[prost(message, repeated, tag = "1")]
+    pub pairs: Vec<Pair>,
+    #[prost(message, repeated, tag = "2")]
+    pub scripts: Vec<ScriptFile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SnapshotMetadata {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub chunk_hashes: Vec<Vec<u8>>,
+}
+
+/// Outcome of applying one chunk of the snapshot being restored.
+#[derive(Debug)]
+pub enum Applied {
+    /// The chunk was accepted and more are needed.
+    Pending,
+    /// The chunk does not match its hash and must be fetched again.
+    Refetch,
+    /// Every chunk arrived and the state they hold matches the app hash.
+    Complete {
+        height: i64,
+        app_hash: Vec<u8>,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    /// The snapshot cannot be restored and another one should be tried.
+    Rejected(String),
+}
+
+/// Snapshot being restored from chunks sent by peers.
+struct Restore {
+    snapshot: Snapshot,
+    app_hash: Vec<u8>,
+    chunk_hashes: Vec<Vec<u8>>,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+/// Snapshots taken by this node, stored under `dir` one directory per height,
+/// and the state of an ongoing restore.
+pub struct Snapshots {
+    dir: PathBuf,
+    scripts_dir: PathBuf,
+    /// Heights at which to take a snapshot, 0 to never take one.
+    interval: i64,
+    /// Number of snapshots to keep, 0 to keep all of them.
+    keep_recent: usize,
+    restore: Option<Restore>,
+}
+
+impl Snapshots {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        scripts_dir: impl Into<PathBuf>,
+        interval: i64,
+        keep_recent: usize,
+    ) -> Self {
+        Self {
+            dir: dir.into(),
+            scripts_dir: scripts_dir.into(),
+            interval,
+            keep_recent,
+            restore: None,
+        }
+    }
+
+    pub fn should_take(&self, height: i64) -> bool {
+        self.interval > 0 && height % self.interval == 0
+    }
+
+    /// Writes a snapshot of `pairs`, which must be every pair committed at
+    /// `height` ordered by key, and deletes the ones past `keep_recent`.
+    pub fn take(&self, height: i64, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> anyhow::Result<Snapshot> {
+        let payload = SnapshotPayload {
+            pairs: pairs
+                .into_iter()
+                .map(|(key, value)| Pair { key, value })
+                .collect(),
+            scripts: read_scripts(&self.scripts_dir)?,
+        }
+        .encode_to_vec();
+
+        let mut chunks = payload.chunks(CHUNK_SIZE).collect::<Vec<_>>();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let snapshot = Snapshot {
+            height: height as u64,
+            format: FORMAT,
+            chunks: chunks.len() as u32,
+            hash: Sha256::digest(&payload).to_vec().into(),
+            metadata: SnapshotMetadata {
+                chunk_hashes: chunks
+                    .iter()
+                    .map(|chunk| Sha256::digest(chunk).to_vec())
+                    .collect(),
+            }
+            .encode_to_vec()
+            .into(),
+        };
+
+        // Written aside and renamed, so a crash never leaves a partial
+        // snapshot behind under its final name.
+        let tmp = self.dir.join(format!("{}.tmp", height));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        for (index, chunk) in chunks.iter().enumerate() {
+            fs::write(tmp.join(index.to_string()), chunk)?;
+        }
+        fs::write(tmp.join(DESCRIPTOR_FILE), snapshot.encode_to_vec())?;
+        let path = self.dir.join(height.to_string());
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::rename(&tmp, path)?;
+
+        if self.keep_recent > 0 {
+            let mut heights = self.heights()?;
+            heights.sort_unstable();
+            let stale = heights.len().saturating_sub(self.keep_recent);
+            for height in &heights[..stale] {
+                fs::remove_dir_all(self.dir.join(height.to_string()))?;
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    fn heights(&self) -> anyhow::Result<Vec<u64>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut heights = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if let Some(height) = entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                heights.push(height);
+            }
+        }
+        Ok(heights)
+    }
+
+    /// Height of the oldest snapshot kept, if any.
+    pub fn oldest_height(&self) -> anyhow::Result<Option<i64>> {
+        Ok(self
+            .heights()?
+            .into_iter()
+            .min()
+            .map(|height| height as i64))
+    }
+
+    pub fn list(&self) -> anyhow::Result<Vec<Snapshot>> {
+        self.heights()?
+            .into_iter()
+            .map(|height| {
+                let path = self.dir.join(height.to_string()).join(DESCRIPTOR_FILE);
+                Ok(Snapshot::decode(fs::read(path)?.as_slice())?)
+            })
+            .collect()
+    }
+
+    pub fn load_chunk(&self, height: u64, format: u32, chunk: u32) -> anyhow::Result<Vec<u8>> {
+        if format != FORMAT {
+            anyhow::bail!("unknown snapshot format {}", format);
+        }
+        let path = self.dir.join(height.to_string()).join(chunk.to_string());
+        Ok(fs::read(path)?)
+    }
+
+    /// Starts restoring `snapshot`, whose state must match `app_hash`.
+    pub fn offer(
+        &mut self,
+        snapshot: Snapshot,
+        app_hash: Vec<u8>,
+    ) -> response_offer_snapshot::Result {
+        if snapshot.format != FORMAT {
+            return response_offer_snapshot::Result::RejectFormat;
+        }
+        let Ok(metadata) = SnapshotMetadata::decode(snapshot.metadata.as_ref()) else {
+            return response_offer_snapshot::Result::Reject;
+        };
+        if snapshot.chunks == 0
+            || snapshot.chunks > MAX_CHUNKS
+            || metadata.chunk_hashes.len() != snapshot.chunks as usize
+        {
+            return response_offer_snapshot::Result::Reject;
+        }
+
+        self.restore = Some(Restore {
+            chunks: vec![None; snapshot.chunks as usize],
+            chunk_hashes: metadata.chunk_hashes,
+            snapshot,
+            app_hash,
+        });
+        response_offer_snapshot::Result::Accept
+    }
+
+    /// Applies the chunk at `index` of the snapshot accepted by `offer`.
+    pub fn apply_chunk(&mut self, index: u32, chunk: Vec<u8>) -> anyhow::Result<Applied> {
+        let Some(restore) = self.restore.as_mut() else {
+            anyhow::bail!("no snapshot is being restored");
+        };
+        let index = index as usize;
+        if index >= restore.chunks.len() {
+            return Ok(Applied::Rejected(format!("chunk {} out of range", index)));
+        }
+        if Sha256::digest(&chunk).as_slice() != restore.chunk_hashes[index] {
+            return Ok(Applied::Refetch);
+        }
+
+        restore.chunks[index] = Some(chunk);
+        if restore.chunks.iter().any(Option::is_none) {
+            return Ok(Applied::Pending);
+        }
+
+        let restore = self.restore.take().expect("restore in progress");
+        let payload = restore
+            .chunks
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect::<Vec<_>>();
+        if Sha256::digest(&payload).as_slice() != restore.snapshot.hash.as_ref() {
+            return Ok(Applied::Rejected("snapshot hash mismatch".to_string()));
+        }
+
+        let payload = SnapshotPayload::decode(payload.as_slice())?;
+        if payload.scripts != read_scripts(&self.scripts_dir)? {
+            return Ok(Applied::Rejected(
+                "snapshot was taken with different scripts".to_string(),
+            ));
+        }
+
+        let pairs = payload
+            .pairs
+            .into_iter()
+            .map(|Pair { key, value }| (key, value))
+            .collect::<Vec<_>>();
+        if pairs.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Ok(Applied::Rejected(
+                "snapshot keys are not sorted".to_string(),
+            ));
+        }
+        if merkle::root_of(&pairs)?.as_slice() != restore.app_hash {
+            return Ok(Applied::Rejected(
+                "snapshot does not match the app hash".to_string(),
+            ));
+        }
+
+        Ok(Applied::Complete {
+            height: restore.snapshot.height as i64,
+            app_hash: restore.app_hash,
+            pairs,
+        })
+    }
+}
+
+/// Every file under `dir` with its path relative to `dir`, ordered by path.
+fn read_scripts(dir: &Path) -> anyhow::Result<Vec<ScriptFile>> {
+    fn visit(root: &Path, dir: &Path, files: &mut Vec<ScriptFile>) -> anyhow::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                visit(root, &path, files)?;
+            } else {
+                files.push(ScriptFile {
+                    path: path.strip_prefix(root)?.to_string_lossy().into_owned(),
+                    content: fs::read(&path)?,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    visit(dir, dir, &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_take_and_restore() {
+        let root = TempDir::new("snapshot");
+        let scripts_dir = root.join("scripts");
+        fs::create_dir_all(&scripts_dir).unwrap();
+        fs::write(scripts_dir.join("kv-set.execute.ts"), "// kv-set").unwrap();
+
+        let pairs = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+        ];
+        let app_hash = merkle::root_of(&pairs).unwrap().to_vec();
+
+        let mut snapshots = Snapshots::new(root.join("snapshots"), &scripts_dir, 1, 1);
+        snapshots.take(1, vec![]).unwrap();
+        let snapshot = snapshots.take(2, pairs.clone()).unwrap();
+        assert_eq!(snapshots.list().unwrap(), vec![snapshot.clone()]);
+        assert_eq!(snapshots.oldest_height().unwrap(), Some(2));
+
+        for chunks in [2, MAX_CHUNKS + 1] {
+            let offered = Snapshot {
+                chunks,
+                ..snapshot.clone()
+            };
+            assert_eq!(
+                snapshots.offer(offered, vec![]),
+                response_offer_snapshot::Result::Reject
+            );
+        }
+
+        assert_eq!(
+            snapshots.offer(snapshot.clone(), vec![]),
+            response_offer_snapshot::Result::Accept
+        );
+        let chunk = snapshots.load_chunk(2, FORMAT, 0).unwrap();
+        assert!(matches!(
+            snapshots.apply_chunk(0, chunk.clone()).unwrap(),
+            Applied::Rejected(_)
+        ));
+
+        snapshots.offer(snapshot, app_hash.clone());
+        assert!(matches!(
+            snapshots.apply_chunk(0, b"garbage".to_vec()).unwrap(),
+            Applied::Refetch
+        ));
+        match snapshots.apply_chunk(0, chunk).unwrap() {
+            Applied::Complete {
+                height,
+                app_hash: restored_hash,
+                pairs: restored,
+            } => {
+                assert_eq!(height, 2);
+                assert_eq!(restored_hash, app_hash);
+                assert_eq!(restored, pairs);
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+}
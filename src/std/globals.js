@@ -0,0 +1,12 @@
+// Globals of scripts written before the standard library, aliases of its
+// first version.
+
+import { context } from "ext:kvstore_app/v1/context.js";
+import { store } from "ext:kvstore_app/v1/store.js";
+// Evaluated into the snapshot, while `Deno.core` is still reachable, see
+// `__comet_bind_ops` in `runtime.js`.
+import "ext:kvstore_app/v1/crypto.js";
+import "ext:kvstore_app/v1/encoding.js";
+
+globalThis.store = store;
+globalThis.context = context;
@@ -0,0 +1,78 @@
+// comet:context@1 - the request being handled and what the script answers.
+
+const { ops } = Deno.core;
+
+/**
+ * Access to the sender, request, chain and block of the current run, and to
+ * events and responses.
+ */
+export const context = {
+  /**
+   * Emits an event with attributes
+   * @param {{
+   *    type: string;
+   *    attributes: {
+   *      key: string;
+   *      value: string;
+   *      index: boolean
+   *    }[];
+   *  }} event - Event object to emit.
+   */
+  emit: (event) => ops.op_ctx_emit(event),
+
+  /**
+   * Sends a response message.
+   * @param {object} response - The message to send.
+   * @returns {Promise<void>} A promise that resolves when the message is sent.
+   */
+  respond: (response) => ops.op_ctx_respond(response),
+
+  /**
+   * Retrieves the sender of the current request.
+   * @returns {string} The sender of the current request.
+   */
+  getSender: () => ops.op_ctx_get_sender(),
+
+  /**
+   * Fetches a request object.
+   * @returns {T} The request object.
+   * @template T
+   */
+  getRequest: () => ops.op_ctx_get_request(),
+
+  /**
+   * Retrieves the chain id set at genesis.
+   * @returns {Promise<string>} A promise that resolves to the chain id.
+   */
+  getChainId: () => ops.op_ctx_get_chain_id(),
+
+  /**
+   * Fetches the parameters the genesis set for the running script.
+   * @returns {Promise<T | null>} A promise that resolves to the parameters, or null if there are none.
+   * @template T
+   */
+  getParams: () => ops.op_ctx_get_params(),
+
+  /**
+   * Retrieves the block the current transaction executes in.
+   * @returns {{ height: number; time: number } | null} The block height and time in milliseconds since the Unix epoch, or null in queries not called from a transaction.
+   */
+  getBlock: () => {
+    const block = ops.op_ctx_get_block();
+    return block === null ? null : { height: block.height, time: block.time };
+  },
+
+  /**
+   * Runs another script within the current transaction or query. The script
+   * sees the running script as its sender, and its writes and events become
+   * the running script's, unless it fails.
+   * @param {string} path - The name of the script, e.g. `"kv-get"`. Its execute script is run if it has one and a transaction is executing, its query script otherwise.
+   * @param {object} request - The request the script gets.
+   * @returns {Promise<T | null>} A promise that resolves to the response of a query script, or null for an execute script.
+   * @throws {Error} If the script fails, in which case its writes and events are dropped.
+   * @template T
+   */
+  call: (path, request) => ops.op_ctx_call(path, request),
+};
+
+export default context;
@@ -0,0 +1,12 @@
+// comet:crypto@1 - hashing.
+
+const { core } = Deno;
+const { ops } = core;
+
+/**
+ * Computes the SHA-256 digest of some data, charged to the run's gas.
+ * @param {string | Uint8Array} data - The data to hash, strings being encoded as UTF-8.
+ * @returns {Uint8Array} The 32-byte digest.
+ */
+export const sha256 = (data) =>
+  ops.op_crypto_sha256(typeof data === "string" ? core.encode(data) : data);
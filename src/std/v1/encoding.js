@@ -0,0 +1,87 @@
+// comet:encoding@1 - conversions between strings and bytes.
+
+const { core } = Deno;
+
+const BASE64_ALPHABET =
+  "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/**
+ * Encodes a string as UTF-8.
+ * @param {string} s - The string to encode.
+ * @returns {Uint8Array} The encoded bytes.
+ */
+export const encodeUtf8 = (s) => core.encode(s);
+
+/**
+ * Decodes UTF-8 bytes into a string.
+ * @param {Uint8Array} bytes - The bytes to decode.
+ * @returns {string} The decoded string.
+ */
+export const decodeUtf8 = (bytes) => core.decode(bytes);
+
+/**
+ * Encodes bytes as lowercase hex.
+ * @param {Uint8Array} bytes - The bytes to encode.
+ * @returns {string} The hex string.
+ */
+export const encodeHex = (bytes) =>
+  Array.from(bytes, (b) => b.toString(16).padStart(2, "0")).join("");
+
+/**
+ * Decodes a hex string into bytes.
+ * @param {string} hex - The hex string, in either case.
+ * @returns {Uint8Array} The decoded bytes.
+ * @throws {Error} If `hex` is not valid hex.
+ */
+export const decodeHex = (hex) => {
+  if (hex.length % 2 !== 0 || !/^[0-9a-fA-F]*$/.test(hex)) {
+    throw new Error("invalid hex string");
+  }
+  const bytes = new Uint8Array(hex.length / 2);
+  for (let i = 0; i < bytes.length; i++) {
+    bytes[i] = parseInt(hex.slice(2 * i, 2 * i + 2), 16);
+  }
+  return bytes;
+};
+
+/**
+ * Encodes bytes as padded base64.
+ * @param {Uint8Array} bytes - The bytes to encode.
+ * @returns {string} The base64 string.
+ */
+export const encodeBase64 = (bytes) => {
+  let out = "";
+  for (let i = 0; i < bytes.length; i += 3) {
+    const n = (bytes[i] << 16) | ((bytes[i + 1] ?? 0) << 8) | (bytes[i + 2] ?? 0);
+    out += BASE64_ALPHABET[(n >> 18) & 63] + BASE64_ALPHABET[(n >> 12) & 63];
+    out += i + 1 < bytes.length ? BASE64_ALPHABET[(n >> 6) & 63] : "=";
+    out += i + 2 < bytes.length ? BASE64_ALPHABET[n & 63] : "=";
+  }
+  return out;
+};
+
+/**
+ * Decodes a padded base64 string into bytes.
+ * @param {string} base64 - The base64 string.
+ * @returns {Uint8Array} The decoded bytes.
+ * @throws {Error} If `base64` is not valid base64.
+ */
+export const decodeBase64 = (base64) => {
+  if (base64.length % 4 !== 0 || !/^[A-Za-z0-9+/]*={0,2}$/.test(base64)) {
+    throw new Error("invalid base64 string");
+  }
+  const padding = base64.endsWith("==") ? 2 : base64.endsWith("=") ? 1 : 0;
+  const bytes = new Uint8Array((base64.length / 4) * 3 - padding);
+  for (let i = 0, j = 0; i < base64.length; i += 4) {
+    const n = [0, 1, 2, 3].reduce(
+      (acc, k) => (acc << 6) | Math.max(BASE64_ALPHABET.indexOf(base64[i + k]), 0),
+      0,
+    );
+    for (const shift of [16, 8, 0]) {
+      if (j < bytes.length) {
+        bytes[j++] = (n >> shift) & 255;
+      }
+    }
+  }
+  return bytes;
+};
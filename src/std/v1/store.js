@@ -0,0 +1,154 @@
+// comet:store@1 - the key-value store of the running script.
+
+const { core } = Deno;
+const { ops } = core;
+
+/** Number of entries fetched per `op_kv_list` call while iterating. */
+const LIST_PAGE_SIZE = 100;
+
+/**
+ * Encodes a string as UTF-8, passing byte arrays through untouched.
+ * @param {string | Uint8Array} v - The string or bytes to encode.
+ * @returns {Uint8Array} The encoded bytes.
+ */
+const toBytes = (v) => (typeof v === "string" ? core.encode(v) : v);
+
+/**
+ * A key-value store interface.
+ *
+ * Keys and values are stored as bytes. Every method accepts either strings,
+ * which are encoded as UTF-8, or `Uint8Array`s. `get`, `delete` and `list`
+ * decode what they return as UTF-8 strings, while `getBytes` and `listBytes`
+ * return the raw bytes.
+ *
+ * Keys live in the namespace of a script: the running script's own unless
+ * another one is selected with `namespace`, which requires a grant in
+ * `permissions.json`.
+ * @param {string} [namespace] - The script whose keys to access.
+ * @returns The store bound to `namespace`.
+ */
+const createStore = (namespace) => {
+  const bound = {
+    /**
+     * Sets a value for a given key in the store.
+     * @function
+     * @param {string | Uint8Array} k - The key to set.
+     * @param {string | Uint8Array} v - The value to set.
+     * @returns {Promise<string>} A promise that resolves to the set value.
+     * @throws {Error} If there's an issue accessing the store or if the key is invalid.
+     */
+    set: async (k, v) =>
+      core.decode(await ops.op_kv_set(toBytes(k), toBytes(v), namespace)),
+
+    /**
+     * Gets the value for a given key from the store.
+     * @function
+     * @param {string | Uint8Array} k - The key to get.
+     * @returns {Promise<string>} A promise that resolves to the value associated with the key, or an empty string if not found.
+     * @throws {Error} If there's an issue accessing the store or if the key is invalid.
+     */
+    get: async (k) => core.decode(await ops.op_kv_get(toBytes(k), namespace)),
+
+    /**
+     * Gets the raw bytes stored under a given key.
+     * @function
+     * @param {string | Uint8Array} k - The key to get.
+     * @returns {Promise<Uint8Array>} A promise that resolves to the value associated with the key.
+     * @throws {Error} If there's an issue accessing the store or if the key is invalid.
+     */
+    getBytes: (k) => ops.op_kv_get(toBytes(k), namespace),
+
+    /**
+     * Deletes a key from the store.
+     * @function
+     * @param {string | Uint8Array} k - The key to delete.
+     * @returns {Promise<string | null>} A promise that resolves to the deleted value, or null if the key did not exist.
+     * @throws {Error} If there's an issue accessing the store or if the key is invalid.
+     */
+    delete: async (k) => {
+      const prev = await ops.op_kv_delete(toBytes(k), namespace);
+      return prev === null ? null : core.decode(prev);
+    },
+
+    /**
+     * Iterates over the entries of the store in key order.
+     * @function
+     * @param {{
+     *    prefix?: string | Uint8Array;
+     *    start?: string | Uint8Array;
+     *    end?: string | Uint8Array;
+     *    limit?: number;
+     *    reverse?: boolean;
+     *  }} [options] - Only keys starting with `prefix`, in the range [`start`, `end`), at most `limit` entries, from the greatest key down if `reverse` is set.
+     * @returns {AsyncGenerator<{ key: string; value: string }>} An async iterator over the selected entries.
+     * @throws {Error} If there's an issue accessing the store.
+     */
+    list: async function* (options = {}) {
+      for await (const { key, value } of bound.listBytes(options)) {
+        yield { key: core.decode(key), value: core.decode(value) };
+      }
+    },
+
+    /**
+     * Same as `list`, but yields the raw bytes of every entry.
+     * @function
+     * @param {object} [options] - Same as for `list`.
+     * @returns {AsyncGenerator<{ key: Uint8Array; value: Uint8Array }>} An async iterator over the selected entries.
+     * @throws {Error} If there's an issue accessing the store.
+     */
+    listBytes: async function* (options = {}) {
+      const { limit = Infinity, reverse = false } = options;
+      const [prefix, start, end] = [options.prefix, options.start, options.end]
+        .map((v) => (v === undefined ? undefined : toBytes(v)));
+
+      let cursor = reverse ? end : start;
+      let remaining = limit;
+      while (remaining > 0) {
+        const pageLimit = Math.min(remaining, LIST_PAGE_SIZE);
+        const page = await ops.op_kv_list({
+          namespace,
+          prefix,
+          start: reverse ? start : cursor,
+          end: reverse ? cursor : end,
+          limit: pageLimit,
+          reverse,
+        });
+
+        for (const [key, value] of page) {
+          yield { key, value };
+        }
+        if (page.length < pageLimit) {
+          return;
+        }
+
+        remaining -= page.length;
+        // `start` is inclusive and `end` exclusive, so appending a NUL byte
+        // moves the cursor just past the last key when going forward.
+        const last = page[page.length - 1][0];
+        if (reverse) {
+          cursor = last;
+        } else {
+          cursor = new Uint8Array(last.length + 1);
+          cursor.set(last);
+        }
+      }
+    },
+
+    /**
+     * Returns the store of another script, to access the keys it owns.
+     * @function
+     * @param {string} name - The name of the script, e.g. `"kv-set"`.
+     * @returns The store bound to that script's namespace.
+     */
+    namespace: (name) => createStore(name),
+  };
+  return bound;
+};
+
+/**
+ * The store bound to the namespace of the running script.
+ * @namespace store
+ */
+export const store = createStore(undefined);
+
+export default store;
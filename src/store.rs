@@ -1,34 +1,1300 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound::{self, Excluded, Included, Unbounded},
+    path::Path,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use deno_core::error::AnyError;
+use tokio::sync::Mutex;
+
+use crate::merkle::{self, Child, Node, NodeKey, TreeReader};
 
 #[async_trait]
 pub trait Store: Sync + Send {
-    async fn set(&mut self, key: String, value: String) -> Result<Option<String>, AnyError>;
-    async fn get(&self, key: String) -> Result<Option<String>, AnyError>;
-    async fn len(&self) -> Result<usize, AnyError>;
+    async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError>;
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError>;
+
+    /// Removes `key` from the store, returning its previous value.
+    async fn delete(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError>;
+
+    /// Returns the key/value pairs selected by `opts`, ordered by key.
+    async fn list(&self, opts: &ListOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError>;
+
+    /// Value of `key` as of the commit at `height`.
+    async fn get_at(&self, key: Vec<u8>, height: i64) -> Result<Option<Vec<u8>>, AnyError>;
+
+    /// Same as `list`, but as of the commit at `height`.
+    async fn list_at(
+        &self,
+        opts: &ListOptions,
+        height: i64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError>;
+
+    /// Merkle root over every key/value pair currently in the store,
+    /// including writes that have not been committed yet.
+    async fn root_hash(&self) -> Result<Vec<u8>, AnyError> {
+        self.root_hash_with(&BTreeMap::new()).await
+    }
+
+    /// Same as `root_hash`, with `writes` applied on top of the pending ones,
+    /// `None` marking a deleted key. Only the paths of the written keys are
+    /// hashed again, see `merkle::update`.
+    async fn root_hash_with(
+        &self,
+        writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Vec<u8>, AnyError>;
+
+    /// Merkle root of the state committed at `height`.
+    async fn root_hash_at(&self, height: i64) -> Result<Vec<u8>, AnyError>;
+
+    /// Path of `key` in the Merkle tree committed at `height`.
+    async fn prove_at(&self, key: Vec<u8>, height: i64) -> Result<merkle::Proof, AnyError>;
+
+    /// Persists every pending write together with the given height and app hash.
+    async fn commit(&mut self, height: i64, app_hash: Vec<u8>) -> Result<(), AnyError>;
+
+    /// Returns the height and app hash of the last commit, if there was one.
+    async fn last_commit(&self) -> Result<Option<(i64, Vec<u8>)>, AnyError>;
+
+    /// Returns the oldest height from which on the state of every height can
+    /// still be read, if any.
+    async fn earliest_height(&self) -> Result<Option<i64>, AnyError>;
+
+    /// Whether the state at the committed `height` can still be read, which
+    /// may be the case below `earliest_height` for heights kept by pruning.
+    async fn retains(&self, height: i64) -> Result<bool, AnyError>;
+
+    /// Discards the state of every height below `height` that `keep` rejects,
+    /// moving `earliest_height` up to `height`.
+    async fn prune(
+        &mut self,
+        height: i64,
+        keep: &(dyn Fn(i64) -> bool + Sync),
+    ) -> Result<(), AnyError>;
+
+    /// Replaces the whole state, history included, with `pairs` as committed
+    /// at `height`, which becomes the earliest height.
+    async fn restore(
+        &mut self,
+        height: i64,
+        app_hash: Vec<u8>,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), AnyError>;
+}
+
+/// Selection of keys for `Store::list`.
+#[derive(Debug, Default, Clone)]
+pub struct ListOptions {
+    /// Only keys starting with this prefix.
+    pub prefix: Option<Vec<u8>>,
+    /// Only keys greater than or equal to this key.
+    pub start: Option<Vec<u8>>,
+    /// Only keys strictly less than this key.
+    pub end: Option<Vec<u8>>,
+    /// Iterate from the greatest key down.
+    pub reverse: bool,
+    /// Maximum number of pairs to return.
+    pub limit: Option<usize>,
 }
 
-pub struct MemoryStore(HashMap<String, String>);
+impl ListOptions {
+    /// Key range selected by the options, or `None` if it is empty.
+    fn range(&self) -> Option<(Bound<Vec<u8>>, Bound<Vec<u8>>)> {
+        let lower = match (&self.prefix, &self.start) {
+            (Some(prefix), Some(start)) => Some(prefix.max(start).clone()),
+            (prefix, start) => prefix.clone().or(start.clone()),
+        };
+        let upper = match (self.prefix.as_deref().and_then(prefix_end), &self.end) {
+            (Some(prefix_end), Some(end)) => Some(prefix_end.min(end.clone())),
+            (prefix_end, end) => prefix_end.or(end.clone()),
+        };
+
+        if let (Some(lower), Some(upper)) = (&lower, &upper) {
+            if lower >= upper {
+                return None;
+            }
+        }
+
+        Some((
+            lower.map_or(Unbounded, Included),
+            upper.map_or(Unbounded, Excluded),
+        ))
+    }
+
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(usize::MAX)
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, if any.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Iterates `map` over the range selected by `opts`, in the requested order.
+fn scan<'a, V>(
+    map: &'a BTreeMap<Vec<u8>, V>,
+    opts: &ListOptions,
+) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a V)> + 'a> {
+    let Some(range) = opts.range() else {
+        return Box::new(std::iter::empty());
+    };
+    if opts.reverse {
+        Box::new(map.range(range).rev())
+    } else {
+        Box::new(map.range(range))
+    }
+}
+
+/// Lists `opts` from a base store with buffered writes layered on top.
+///
+/// Every buffered write can hide at most one base pair, so asking the base
+/// for `limit + writes` pairs is always enough to fill the page.
+async fn list_overlay(
+    writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    base: impl std::future::Future<Output = Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError>>,
+    opts: &ListOptions,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+    let mut merged = base.await?.into_iter().collect::<BTreeMap<_, _>>();
+    for (key, value) in scan(writes, opts) {
+        match value {
+            Some(value) => merged.insert(key.clone(), value.clone()),
+            None => merged.remove(key),
+        };
+    }
+
+    let merged = merged.into_iter();
+    let merged: Box<dyn Iterator<Item = _>> = if opts.reverse {
+        Box::new(merged.rev())
+    } else {
+        Box::new(merged)
+    };
+    Ok(merged.take(opts.limit()).collect())
+}
+
+/// Options for the base store underneath the writes buffered for `opts`.
+fn overlay_base_opts(
+    writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    opts: &ListOptions,
+) -> ListOptions {
+    ListOptions {
+        limit: opts
+            .limit
+            .map(|limit| limit.saturating_add(scan(writes, opts).count())),
+        ..opts.clone()
+    }
+}
+
+/// `writes` applied on top of `pending`.
+fn overlay(
+    pending: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+    let mut changes = pending.clone();
+    changes.extend(writes.iter().map(|(k, v)| (k.clone(), v.clone())));
+    changes
+}
+
+/// Store that keeps everything in memory, for tests and throwaway nodes.
+pub struct MemoryStore {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Writes since the last commit, `None` marking a deleted key.
+    dirty: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Every committed version of every key, by height.
+    history: BTreeMap<Vec<u8>, BTreeMap<i64, Option<Vec<u8>>>>,
+    last_commit: Option<(i64, Vec<u8>)>,
+    earliest_height: Option<i64>,
+    /// Heights below `earliest_height` that were kept when pruning.
+    kept: BTreeSet<i64>,
+    /// Nodes of the Merkle tree of every retained height.
+    nodes: BTreeMap<NodeKey, Node>,
+    /// Root of the Merkle tree committed at every retained height.
+    roots: BTreeMap<i64, Option<Child>>,
+    /// Nodes that the commit at every height made stale.
+    stale: BTreeMap<i64, Vec<NodeKey>>,
+}
 
 impl MemoryStore {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self {
+            data: BTreeMap::new(),
+            dirty: BTreeMap::new(),
+            history: BTreeMap::new(),
+            last_commit: None,
+            earliest_height: None,
+            kept: BTreeSet::new(),
+            nodes: BTreeMap::new(),
+            roots: BTreeMap::new(),
+            stale: BTreeMap::new(),
+        }
+    }
+
+    fn root_at(&self, height: i64) -> Result<Option<Child>, AnyError> {
+        self.roots
+            .get(&height)
+            .copied()
+            .ok_or_else(|| AnyError::msg(format!("no state at height {}", height)))
+    }
+
+    fn latest_root(&self) -> Option<Child> {
+        self.roots.values().next_back().copied().flatten()
     }
 }
 
 #[async_trait]
 impl Store for MemoryStore {
-    async fn set(&mut self, key: String, value: String) -> Result<Option<String>, AnyError> {
-        Ok(self.0.insert(key, value))
+    async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        self.dirty.insert(key.clone(), Some(value.clone()));
+        Ok(self.data.insert(key, value))
+    }
+
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        Ok(self.data.get(&key).cloned())
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        self.dirty.insert(key.clone(), None);
+        Ok(self.data.remove(&key))
+    }
+
+    async fn list(&self, opts: &ListOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        Ok(scan(&self.data, opts)
+            .take(opts.limit())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    async fn get_at(&self, key: Vec<u8>, height: i64) -> Result<Option<Vec<u8>>, AnyError> {
+        Ok(self
+            .history
+            .get(&key)
+            .and_then(|versions| versions.range(..=height).next_back())
+            .and_then(|(_, value)| value.clone()))
+    }
+
+    async fn list_at(
+        &self,
+        opts: &ListOptions,
+        height: i64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        Ok(scan(&self.history, opts)
+            .filter_map(|(key, versions)| {
+                let (_, value) = versions.range(..=height).next_back()?;
+                Some((key.clone(), value.clone()?))
+            })
+            .take(opts.limit())
+            .collect())
+    }
+
+    async fn root_hash_with(
+        &self,
+        writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Vec<u8>, AnyError> {
+        let changes = overlay(&self.dirty, writes);
+        let version = self
+            .last_commit
+            .as_ref()
+            .map_or(0, |(height, _)| height + 1);
+        let update = merkle::update(&self.nodes, self.latest_root(), version, &changes)?;
+        Ok(update.root_hash().to_vec())
+    }
+
+    async fn root_hash_at(&self, height: i64) -> Result<Vec<u8>, AnyError> {
+        Ok(self
+            .root_at(height)?
+            .map_or(merkle::EMPTY, |root| root.hash)
+            .to_vec())
+    }
+
+    async fn prove_at(&self, key: Vec<u8>, height: i64) -> Result<merkle::Proof, AnyError> {
+        merkle::prove(&self.nodes, self.root_at(height)?, &key)
+    }
+
+    async fn commit(&mut self, height: i64, app_hash: Vec<u8>) -> Result<(), AnyError> {
+        let changes = std::mem::take(&mut self.dirty);
+        let update = merkle::update(&self.nodes, self.latest_root(), height, &changes)?;
+        self.nodes.extend(update.nodes);
+        self.stale.insert(height, update.stale);
+        self.roots.insert(height, update.root);
+
+        for (key, value) in changes {
+            self.history.entry(key).or_default().insert(height, value);
+        }
+        self.earliest_height.get_or_insert(height);
+        self.last_commit = Some((height, app_hash));
+        Ok(())
+    }
+
+    async fn last_commit(&self) -> Result<Option<(i64, Vec<u8>)>, AnyError> {
+        Ok(self.last_commit.clone())
+    }
+
+    async fn earliest_height(&self) -> Result<Option<i64>, AnyError> {
+        Ok(self.earliest_height)
+    }
+
+    async fn retains(&self, height: i64) -> Result<bool, AnyError> {
+        Ok(self
+            .earliest_height
+            .is_some_and(|earliest| height >= earliest || self.kept.contains(&height)))
+    }
+
+    async fn prune(
+        &mut self,
+        height: i64,
+        keep: &(dyn Fn(i64) -> bool + Sync),
+    ) -> Result<(), AnyError> {
+        let Some(earliest) = self.earliest_height.filter(|&e| e < height) else {
+            return Ok(());
+        };
+        self.kept.extend((earliest..height).filter(|&h| keep(h)));
+
+        // A version is visible from its height up to the next one, and is no
+        // longer needed once none of those heights is retained.
+        for versions in self.history.values_mut() {
+            let heights: Vec<i64> = versions.keys().copied().collect();
+            for pair in heights.windows(2) {
+                let (from, to) = (pair[0], pair[1]);
+                if to <= height && self.kept.range(from..to).next().is_none() {
+                    versions.remove(&from);
+                }
+            }
+        }
+
+        // So is a tree node, from the height that wrote it up to the one that
+        // made it stale.
+        for next in earliest + 1..=height {
+            for key in self.stale.remove(&next).unwrap_or_default() {
+                if self.kept.range(key.version..next).next().is_none() {
+                    self.nodes.remove(&key);
+                }
+            }
+        }
+        for pruned in earliest..height {
+            if !self.kept.contains(&pruned) {
+                self.roots.remove(&pruned);
+            }
+        }
+        self.earliest_height = Some(height);
+        Ok(())
+    }
+
+    async fn restore(
+        &mut self,
+        height: i64,
+        app_hash: Vec<u8>,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), AnyError> {
+        *self = Self::new();
+        for (key, value) in pairs {
+            self.history
+                .entry(key.clone())
+                .or_default()
+                .insert(height, Some(value.clone()));
+            self.data.insert(key, value);
+        }
+        let changes = self
+            .data
+            .iter()
+            .map(|(key, value)| (key.clone(), Some(value.clone())))
+            .collect();
+        let update = merkle::update(&self.nodes, None, height, &changes)?;
+        self.nodes = update.nodes;
+        self.roots.insert(height, update.root);
+        self.earliest_height = Some(height);
+        self.last_commit = Some((height, app_hash));
+        Ok(())
+    }
+}
+
+const DATA_PREFIX: &[u8] = b"d/";
+/// First key past every `DATA_PREFIX` key.
+const DATA_END: &[u8] = b"d0";
+const HISTORY_PREFIX: &[u8] = b"h/";
+/// First key past every `HISTORY_PREFIX` key.
+const HISTORY_END: &[u8] = b"h0";
+const HEIGHT_KEY: &[u8] = b"m/height";
+const APP_HASH_KEY: &[u8] = b"m/app_hash";
+const EARLIEST_HEIGHT_KEY: &[u8] = b"m/earliest_height";
+/// Keys written at every height, as `c/` + height + key, used for pruning.
+const CHANGES_PREFIX: &[u8] = b"c/";
+/// Heights below the earliest height that were kept when pruning.
+const KEPT_PREFIX: &[u8] = b"k/";
+/// Nodes of the Merkle tree, as `n/` + encoded `NodeKey`.
+const NODE_PREFIX: &[u8] = b"n/";
+/// Root of the Merkle tree committed at every height, as `r/` + height.
+const ROOT_PREFIX: &[u8] = b"r/";
+/// Nodes made stale at every height, as `s/` + height + encoded `NodeKey`.
+const STALE_PREFIX: &[u8] = b"s/";
+
+/// Order preserving encoding of `key` for use inside a longer database key.
+/// NUL bytes are escaped as `00 ff` so the `00 01` terminator appended by
+/// `history_key` sorts before any continuation of the key.
+fn escape_key(key: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(key.len() + 2);
+    for &b in key {
+        escaped.push(b);
+        if b == 0 {
+            escaped.push(0xff);
+        }
+    }
+    escaped
+}
+
+fn unescape_key(escaped: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(escaped.len());
+    let mut iter = escaped.iter();
+    while let Some(&b) = iter.next() {
+        key.push(b);
+        if b == 0 {
+            iter.next();
+        }
+    }
+    key
+}
+
+/// Database key of the version of `key` written at `height`. Versions of a
+/// key are adjacent and ordered by height, and keys are ordered as in `data`.
+fn history_key(key: &[u8], height: i64) -> Vec<u8> {
+    [
+        HISTORY_PREFIX,
+        &escape_key(key),
+        &[0, 1],
+        &height.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn decode_history_key(raw: &[u8]) -> Result<(Vec<u8>, i64), AnyError> {
+    let (key, height) = raw[HISTORY_PREFIX.len()..].split_at(raw.len() - HISTORY_PREFIX.len() - 8);
+    let key = unescape_key(&key[..key.len() - 2]);
+    Ok((key, i64::from_be_bytes(height.try_into()?)))
+}
+
+fn changes_key(height: i64, key: &[u8]) -> Vec<u8> {
+    [CHANGES_PREFIX, &height.to_be_bytes(), key].concat()
+}
+
+fn kept_key(height: i64) -> Vec<u8> {
+    [KEPT_PREFIX, &height.to_be_bytes()].concat()
+}
+
+fn node_key(key: &NodeKey) -> Vec<u8> {
+    [NODE_PREFIX, &key.encode()].concat()
+}
+
+fn root_key(height: i64) -> Vec<u8> {
+    [ROOT_PREFIX, &height.to_be_bytes()].concat()
+}
+
+fn stale_key(height: i64, key: Option<&NodeKey>) -> Vec<u8> {
+    let key = key.map(NodeKey::encode).unwrap_or_default();
+    [STALE_PREFIX, &height.to_be_bytes(), &key].concat()
+}
+
+fn encode_version(value: Option<Vec<u8>>) -> Vec<u8> {
+    match value {
+        Some(value) => [&[1], value.as_slice()].concat(),
+        None => vec![0],
+    }
+}
+
+fn decode_version(raw: &[u8]) -> Result<Option<Vec<u8>>, AnyError> {
+    match raw.split_first() {
+        Some((1, value)) => Ok(Some(value.to_vec())),
+        Some((0, _)) => Ok(None),
+        _ => Err(AnyError::msg("corrupted version entry")),
+    }
+}
+
+/// Store backed by an embedded sled database.
+///
+/// Writes and deletions are buffered in memory and only reach the database on
+/// `commit`, where they are applied in a single atomic batch along with the
+/// commit height and app hash. A crash at any point therefore leaves the
+/// database at the last fully committed block.
+///
+/// Besides the latest value of every key under `d/`, each commit records the
+/// versions it wrote under `h/`, which is what `get_at` and `list_at` read.
+/// The keys it wrote are indexed by height under `c/`, so pruning a height
+/// only visits the versions that height made obsolete.
+///
+/// Likewise, each commit writes the nodes of the Merkle tree on the paths of
+/// the keys it changed under `n/` and its root under `r/`, and indexes the
+/// nodes they replace under `s/` for pruning.
+pub struct DiskStore {
+    db: sled::Db,
+    /// Uncommitted writes, `None` marking a deleted key.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl DiskStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AnyError> {
+        Ok(Self {
+            db: sled::open(path)?,
+            pending: BTreeMap::new(),
+        })
+    }
+
+    fn data_key(key: &[u8]) -> Vec<u8> {
+        [DATA_PREFIX, key].concat()
+    }
+
+    fn get_committed(&self, key: &[u8]) -> Result<Option<Vec<u8>>, AnyError> {
+        Ok(self.db.get(Self::data_key(key))?.map(|v| v.to_vec()))
+    }
+
+    fn list_committed(&self, opts: &ListOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        let Some((lower, upper)) = opts.range() else {
+            return Ok(vec![]);
+        };
+        let lower = match lower {
+            Included(key) => Included(Self::data_key(&key)),
+            _ => Included(DATA_PREFIX.to_vec()),
+        };
+        let upper = match upper {
+            Excluded(key) => Excluded(Self::data_key(&key)),
+            _ => Excluded(DATA_END.to_vec()),
+        };
+
+        let iter = self.db.range((lower, upper));
+        let iter: Box<dyn Iterator<Item = _>> = if opts.reverse {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+
+        iter.take(opts.limit())
+            .map(|item| -> Result<_, AnyError> {
+                let (key, value) = item?;
+                Ok((key[DATA_PREFIX.len()..].to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn get_i64(&self, key: &[u8]) -> Result<Option<i64>, AnyError> {
+        match self.db.get(key)? {
+            Some(v) => Ok(Some(i64::from_be_bytes(v.as_ref().try_into()?))),
+            None => Ok(None),
+        }
+    }
+
+    fn root_at(&self, height: i64) -> Result<Option<Child>, AnyError> {
+        match self.db.get(root_key(height))? {
+            Some(raw) => Child::decode_root(&raw),
+            None => Err(AnyError::msg(format!("no state at height {}", height))),
+        }
+    }
+
+    fn latest_root(&self) -> Result<Option<Child>, AnyError> {
+        match self.get_i64(HEIGHT_KEY)? {
+            Some(height) => self.root_at(height),
+            None => Ok(None),
+        }
+    }
+}
+
+impl TreeReader for DiskStore {
+    fn node(&self, key: &NodeKey) -> Result<Option<Node>, AnyError> {
+        match self.db.get(node_key(key))? {
+            Some(raw) => Ok(Some(Node::decode(&raw)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for DiskStore {
+    async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.pending.insert(key.clone(), Some(value)) {
+            Some(prev) => Ok(prev),
+            None => self.get_committed(&key),
+        }
+    }
+
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.pending.get(&key) {
+            Some(v) => Ok(v.clone()),
+            None => self.get_committed(&key),
+        }
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.pending.insert(key.clone(), None) {
+            Some(prev) => Ok(prev),
+            None => self.get_committed(&key),
+        }
+    }
+
+    async fn list(&self, opts: &ListOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        let base_opts = overlay_base_opts(&self.pending, opts);
+        let base = async { self.list_committed(&base_opts) };
+        list_overlay(&self.pending, base, opts).await
+    }
+
+    async fn get_at(&self, key: Vec<u8>, height: i64) -> Result<Option<Vec<u8>>, AnyError> {
+        let mut versions = self
+            .db
+            .range(history_key(&key, 0)..=history_key(&key, height));
+        match versions.next_back() {
+            Some(item) => decode_version(&item?.1),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_at(
+        &self,
+        opts: &ListOptions,
+        height: i64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        let Some((lower, upper)) = opts.range() else {
+            return Ok(vec![]);
+        };
+        let lower = match lower {
+            Included(key) => Included([HISTORY_PREFIX, &escape_key(&key)].concat()),
+            _ => Included(HISTORY_PREFIX.to_vec()),
+        };
+        let upper = match upper {
+            Excluded(key) => Excluded([HISTORY_PREFIX, &escape_key(&key)].concat()),
+            _ => Excluded(HISTORY_END.to_vec()),
+        };
+
+        let iter = self.db.range((lower, upper));
+        let iter: Box<dyn Iterator<Item = _>> = if opts.reverse {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+
+        // The versions of a key are adjacent, by ascending height or by
+        // descending height in reverse, and the visible one is the latest at
+        // or below `height`. Scanning stops once the page is full.
+        let limit = opts.limit();
+        let mut pairs = vec![];
+        let mut current: Option<(Vec<u8>, Option<Vec<u8>>)> = None;
+        for item in iter {
+            let (raw_key, raw_value) = item?;
+            let (key, version) = decode_history_key(&raw_key)?;
+            if current.as_ref().is_some_and(|(k, _)| *k != key) {
+                if let Some((k, Some(v))) = current.take() {
+                    pairs.push((k, v));
+                    if pairs.len() >= limit {
+                        break;
+                    }
+                }
+            }
+            let found = current.is_some();
+            if version <= height && !(opts.reverse && found) {
+                current = Some((key, decode_version(&raw_value)?));
+            }
+        }
+        if let Some((k, Some(v))) = current {
+            pairs.push((k, v));
+        }
+
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    async fn root_hash_with(
+        &self,
+        writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Vec<u8>, AnyError> {
+        let changes = overlay(&self.pending, writes);
+        let version = self.get_i64(HEIGHT_KEY)?.map_or(0, |height| height + 1);
+        let update = merkle::update(self, self.latest_root()?, version, &changes)?;
+        Ok(update.root_hash().to_vec())
+    }
+
+    async fn root_hash_at(&self, height: i64) -> Result<Vec<u8>, AnyError> {
+        Ok(self
+            .root_at(height)?
+            .map_or(merkle::EMPTY, |root| root.hash)
+            .to_vec())
+    }
+
+    async fn prove_at(&self, key: Vec<u8>, height: i64) -> Result<merkle::Proof, AnyError> {
+        merkle::prove(self, self.root_at(height)?, &key)
+    }
+
+    async fn commit(&mut self, height: i64, app_hash: Vec<u8>) -> Result<(), AnyError> {
+        let mut batch = sled::Batch::default();
+        let update = merkle::update(&*self, self.latest_root()?, height, &self.pending)?;
+        for (key, node) in &update.nodes {
+            batch.insert(node_key(key), node.encode());
+        }
+        for key in &update.stale {
+            batch.insert(stale_key(height, Some(key)), vec![]);
+        }
+        batch.insert(root_key(height), Child::encode_root(&update.root));
+
+        for (key, value) in std::mem::take(&mut self.pending) {
+            batch.insert(history_key(&key, height), encode_version(value.clone()));
+            batch.insert(changes_key(height, &key), vec![]);
+            match value {
+                Some(value) => batch.insert(Self::data_key(&key), value),
+                None => batch.remove(Self::data_key(&key)),
+            }
+        }
+        batch.insert(HEIGHT_KEY, &height.to_be_bytes());
+        batch.insert(APP_HASH_KEY, app_hash);
+        if self.get_i64(EARLIEST_HEIGHT_KEY)?.is_none() {
+            batch.insert(EARLIEST_HEIGHT_KEY, &height.to_be_bytes());
+        }
+
+        self.db.apply_batch(batch)?;
+        self.db.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn last_commit(&self) -> Result<Option<(i64, Vec<u8>)>, AnyError> {
+        let (Some(height), Some(app_hash)) =
+            (self.get_i64(HEIGHT_KEY)?, self.db.get(APP_HASH_KEY)?)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some((height, app_hash.to_vec())))
+    }
+
+    async fn earliest_height(&self) -> Result<Option<i64>, AnyError> {
+        self.get_i64(EARLIEST_HEIGHT_KEY)
+    }
+
+    async fn retains(&self, height: i64) -> Result<bool, AnyError> {
+        match self.get_i64(EARLIEST_HEIGHT_KEY)? {
+            Some(earliest) if height >= earliest => Ok(true),
+            Some(_) => Ok(self.db.contains_key(kept_key(height))?),
+            None => Ok(false),
+        }
+    }
+
+    async fn prune(
+        &mut self,
+        height: i64,
+        keep: &(dyn Fn(i64) -> bool + Sync),
+    ) -> Result<(), AnyError> {
+        let Some(earliest) = self.get_i64(EARLIEST_HEIGHT_KEY)?.filter(|&e| e < height) else {
+            return Ok(());
+        };
+
+        let mut batch = sled::Batch::default();
+        let mut kept = BTreeSet::new();
+        // Whether any height in `from..to` is kept.
+        let is_kept = |kept: &BTreeSet<i64>, from: i64, to: i64| -> Result<bool, AnyError> {
+            Ok(kept.range(from..to).next().is_some()
+                || self.db.range(kept_key(from)..kept_key(to)).next().is_some())
+        };
+        for pruned in earliest..height {
+            if keep(pruned) {
+                batch.insert(kept_key(pruned), vec![]);
+                kept.insert(pruned);
+            } else {
+                batch.remove(root_key(pruned));
+            }
+
+            // Every key written at the next height has a previous version
+            // visible from its own height up to `pruned`, which is obsolete
+            // unless one of those heights is kept.
+            let next = pruned + 1;
+            for item in self.db.scan_prefix(changes_key(next, &[])) {
+                let (raw_key, _) = item?;
+                let key = &raw_key[CHANGES_PREFIX.len() + 8..];
+                batch.remove(raw_key.clone());
+
+                let mut previous = self.db.range(history_key(key, 0)..history_key(key, next));
+                let Some(item) = previous.next_back() else {
+                    continue;
+                };
+                let (version_key, _) = item?;
+                let (_, from) = decode_history_key(&version_key)?;
+                if !is_kept(&kept, from, next)? {
+                    batch.remove(version_key);
+                }
+            }
+
+            // So is every tree node it made stale, from its own height on.
+            for item in self.db.scan_prefix(stale_key(next, None)) {
+                let (raw_key, _) = item?;
+                let key = NodeKey::decode(&raw_key[STALE_PREFIX.len() + 8..])?;
+                batch.remove(raw_key);
+                if !is_kept(&kept, key.version, next)? {
+                    batch.remove(node_key(&key));
+                }
+            }
+        }
+        batch.insert(EARLIEST_HEIGHT_KEY, &height.to_be_bytes());
+
+        self.db.apply_batch(batch)?;
+        self.db.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn restore(
+        &mut self,
+        height: i64,
+        app_hash: Vec<u8>,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), AnyError> {
+        let mut batch = sled::Batch::default();
+        for key in self.db.iter().keys() {
+            batch.remove(key?);
+        }
+        let changes = pairs
+            .iter()
+            .map(|(key, value)| (key.clone(), Some(value.clone())))
+            .collect();
+        let update = merkle::update(&BTreeMap::<NodeKey, Node>::new(), None, height, &changes)?;
+        for (key, node) in &update.nodes {
+            batch.insert(node_key(key), node.encode());
+        }
+        batch.insert(root_key(height), Child::encode_root(&update.root));
+        for (key, value) in pairs {
+            batch.insert(
+                history_key(&key, height),
+                encode_version(Some(value.clone())),
+            );
+            batch.insert(changes_key(height, &key), vec![]);
+            batch.insert(Self::data_key(&key), value);
+        }
+        batch.insert(HEIGHT_KEY, &height.to_be_bytes());
+        batch.insert(APP_HASH_KEY, app_hash);
+        batch.insert(EARLIEST_HEIGHT_KEY, &height.to_be_bytes());
+
+        self.pending.clear();
+        self.db.apply_batch(batch)?;
+        self.db.flush_async().await?;
+
+        Ok(())
+    }
+}
+
+/// Write buffer layered over another store.
+///
+/// Reads see the buffered writes first and fall through to the parent
+/// otherwise. Nothing reaches the parent until `flush` is called, so dropping
+/// the cache discards every write made through it.
+pub struct CacheStore {
+    parent: Arc<Mutex<dyn Store>>,
+    /// Buffered writes, `None` marking a deleted key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl CacheStore {
+    pub fn new(parent: Arc<Mutex<dyn Store>>) -> Self {
+        Self {
+            parent,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Applies the buffered writes to the parent store.
+    pub async fn flush(&mut self) -> Result<(), AnyError> {
+        let mut parent = self.parent.lock().await;
+        for (key, value) in std::mem::take(&mut self.writes) {
+            match value {
+                Some(value) => parent.set(key, value).await?,
+                None => parent.delete(key).await?,
+            };
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Store for CacheStore {
+    async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.writes.insert(key.clone(), Some(value)) {
+            Some(prev) => Ok(prev),
+            None => self.parent.lock().await.get(key).await,
+        }
+    }
+
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.writes.get(&key) {
+            Some(v) => Ok(v.clone()),
+            None => self.parent.lock().await.get(key).await,
+        }
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        match self.writes.insert(key.clone(), None) {
+            Some(prev) => Ok(prev),
+            None => self.parent.lock().await.get(key).await,
+        }
+    }
+
+    async fn list(&self, opts: &ListOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        let base_opts = overlay_base_opts(&self.writes, opts);
+        let base = async { self.parent.lock().await.list(&base_opts).await };
+        list_overlay(&self.writes, base, opts).await
+    }
+
+    async fn get_at(&self, key: Vec<u8>, height: i64) -> Result<Option<Vec<u8>>, AnyError> {
+        self.parent.lock().await.get_at(key, height).await
+    }
+
+    async fn list_at(
+        &self,
+        opts: &ListOptions,
+        height: i64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        self.parent.lock().await.list_at(opts, height).await
+    }
+
+    async fn root_hash_with(
+        &self,
+        writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Vec<u8>, AnyError> {
+        let writes = overlay(&self.writes, writes);
+        self.parent.lock().await.root_hash_with(&writes).await
+    }
+
+    async fn root_hash_at(&self, height: i64) -> Result<Vec<u8>, AnyError> {
+        self.parent.lock().await.root_hash_at(height).await
+    }
+
+    async fn prove_at(&self, key: Vec<u8>, height: i64) -> Result<merkle::Proof, AnyError> {
+        self.parent.lock().await.prove_at(key, height).await
+    }
+
+    async fn commit(&mut self, _height: i64, _app_hash: Vec<u8>) -> Result<(), AnyError> {
+        Err(AnyError::msg("cache store must be flushed, not committed"))
+    }
+
+    async fn last_commit(&self) -> Result<Option<(i64, Vec<u8>)>, AnyError> {
+        self.parent.lock().await.last_commit().await
+    }
+
+    async fn earliest_height(&self) -> Result<Option<i64>, AnyError> {
+        self.parent.lock().await.earliest_height().await
+    }
+
+    async fn retains(&self, height: i64) -> Result<bool, AnyError> {
+        self.parent.lock().await.retains(height).await
     }
 
-    async fn get(&self, key: String) -> Result<Option<String>, AnyError> {
-        Ok(self.0.get(&key).cloned())
+    async fn prune(
+        &mut self,
+        _height: i64,
+        _keep: &(dyn Fn(i64) -> bool + Sync),
+    ) -> Result<(), AnyError> {
+        Err(AnyError::msg("cache store must be flushed, not pruned"))
     }
 
-    async fn len(&self) -> Result<usize, AnyError> {
-        Ok(self.0.len())
+    async fn restore(
+        &mut self,
+        _height: i64,
+        _app_hash: Vec<u8>,
+        _pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), AnyError> {
+        Err(AnyError::msg("cache store must be flushed, not restored"))
+    }
+}
+
+/// Read-only view of a store as of the commit at a past height.
+pub struct HistoricalStore {
+    parent: Arc<Mutex<dyn Store>>,
+    height: i64,
+}
+
+impl HistoricalStore {
+    pub fn new(parent: Arc<Mutex<dyn Store>>, height: i64) -> Self {
+        Self { parent, height }
+    }
+}
+
+#[async_trait]
+impl Store for HistoricalStore {
+    async fn set(&mut self, _key: Vec<u8>, _value: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        Err(AnyError::msg("historical state is read-only"))
+    }
+
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        self.parent.lock().await.get_at(key, self.height).await
+    }
+
+    async fn delete(&mut self, _key: Vec<u8>) -> Result<Option<Vec<u8>>, AnyError> {
+        Err(AnyError::msg("historical state is read-only"))
+    }
+
+    async fn list(&self, opts: &ListOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        self.parent.lock().await.list_at(opts, self.height).await
+    }
+
+    async fn get_at(&self, key: Vec<u8>, height: i64) -> Result<Option<Vec<u8>>, AnyError> {
+        self.parent.lock().await.get_at(key, height).await
+    }
+
+    async fn list_at(
+        &self,
+        opts: &ListOptions,
+        height: i64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, AnyError> {
+        self.parent.lock().await.list_at(opts, height).await
+    }
+
+    async fn root_hash_with(
+        &self,
+        writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Vec<u8>, AnyError> {
+        if !writes.is_empty() {
+            return Err(AnyError::msg("historical state is read-only"));
+        }
+        self.parent.lock().await.root_hash_at(self.height).await
+    }
+
+    async fn root_hash_at(&self, height: i64) -> Result<Vec<u8>, AnyError> {
+        self.parent.lock().await.root_hash_at(height).await
+    }
+
+    async fn prove_at(&self, key: Vec<u8>, height: i64) -> Result<merkle::Proof, AnyError> {
+        self.parent.lock().await.prove_at(key, height).await
+    }
+
+    async fn commit(&mut self, _height: i64, _app_hash: Vec<u8>) -> Result<(), AnyError> {
+        Err(AnyError::msg("historical state is read-only"))
+    }
+
+    async fn last_commit(&self) -> Result<Option<(i64, Vec<u8>)>, AnyError> {
+        self.parent.lock().await.last_commit().await
+    }
+
+    async fn earliest_height(&self) -> Result<Option<i64>, AnyError> {
+        self.parent.lock().await.earliest_height().await
+    }
+
+    async fn retains(&self, height: i64) -> Result<bool, AnyError> {
+        self.parent.lock().await.retains(height).await
+    }
+
+    async fn prune(
+        &mut self,
+        _height: i64,
+        _keep: &(dyn Fn(i64) -> bool + Sync),
+    ) -> Result<(), AnyError> {
+        Err(AnyError::msg("historical state is read-only"))
+    }
+
+    async fn restore(
+        &mut self,
+        _height: i64,
+        _app_hash: Vec<u8>,
+        _pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), AnyError> {
+        Err(AnyError::msg("historical state is read-only"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn test_disk_store_recovery() {
+        let path = TempDir::new("store");
+
+        {
+            let mut store = DiskStore::open(&path).unwrap();
+            store.set("a".into(), "1".into()).await.unwrap();
+            store.commit(1, vec![1]).await.unwrap();
+
+            // never committed, must not survive a restart
+            store.set("b".into(), "2".into()).await.unwrap();
+        }
+
+        let store = DiskStore::open(&path).unwrap();
+        assert_eq!(store.last_commit().await.unwrap(), Some((1, vec![1])));
+        assert_eq!(store.get("a".into()).await.unwrap(), Some("1".into()));
+        assert_eq!(store.get("b".into()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_history() {
+        let path = TempDir::new("history");
+        let mut store = DiskStore::open(&path).unwrap();
+
+        store.set("a".into(), "1".into()).await.unwrap();
+        store.set("a\0".into(), "x".into()).await.unwrap();
+        store.commit(1, vec![]).await.unwrap();
+        store.set("a".into(), "2".into()).await.unwrap();
+        store.set("b".into(), "3".into()).await.unwrap();
+        store.commit(2, vec![]).await.unwrap();
+        store.delete("a".into()).await.unwrap();
+        store.commit(3, vec![]).await.unwrap();
+
+        assert_eq!(store.get_at("a".into(), 1).await.unwrap(), Some("1".into()));
+        assert_eq!(store.get_at("a".into(), 2).await.unwrap(), Some("2".into()));
+        assert_eq!(store.get_at("a".into(), 3).await.unwrap(), None);
+
+        let pairs = |items: &[(&str, &str)]| {
+            items
+                .iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        let all = ListOptions::default();
+        assert_eq!(
+            store.list_at(&all, 1).await.unwrap(),
+            pairs(&[("a", "1"), ("a\0", "x")])
+        );
+        assert_eq!(
+            store.list_at(&all, 2).await.unwrap(),
+            pairs(&[("a", "2"), ("a\0", "x"), ("b", "3")])
+        );
+        assert_eq!(
+            store.list_at(&all, 3).await.unwrap(),
+            pairs(&[("a\0", "x"), ("b", "3")])
+        );
+        let reverse_page = ListOptions {
+            reverse: true,
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            store.list_at(&reverse_page, 2).await.unwrap(),
+            pairs(&[("b", "3"), ("a\0", "x")])
+        );
+        assert_eq!(
+            store.list_at(&reverse_page, 1).await.unwrap(),
+            pairs(&[("a\0", "x"), ("a", "1")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_pruning() {
+        let path = TempDir::new("pruning");
+        let mut store = DiskStore::open(&path).unwrap();
+
+        for height in 1..=5 {
+            store
+                .set("a".into(), height.to_string().into())
+                .await
+                .unwrap();
+            if height == 1 {
+                store.set("b".into(), "b".into()).await.unwrap();
+            }
+            store.commit(height, vec![]).await.unwrap();
+        }
+        store.prune(4, &|height| height == 2).await.unwrap();
+
+        assert_eq!(store.earliest_height().await.unwrap(), Some(4));
+        assert!(!store.retains(1).await.unwrap());
+        assert!(store.retains(2).await.unwrap());
+        assert!(!store.retains(3).await.unwrap());
+        assert!(store.retains(4).await.unwrap());
+
+        assert_eq!(store.get_at("a".into(), 2).await.unwrap(), Some("2".into()));
+        assert_eq!(store.get_at("a".into(), 4).await.unwrap(), Some("4".into()));
+        assert_eq!(store.get_at("b".into(), 4).await.unwrap(), Some("b".into()));
+        assert!(!store.db.contains_key(history_key(b"a", 1)).unwrap());
+        assert!(!store.db.contains_key(history_key(b"a", 3)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_disk_store_tree() {
+        let path = TempDir::new("tree");
+        let mut store = DiskStore::open(&path).unwrap();
+
+        store.set("a".into(), "1".into()).await.unwrap();
+        store.set("b".into(), "2".into()).await.unwrap();
+        store.commit(1, vec![]).await.unwrap();
+        store.set("b".into(), "3".into()).await.unwrap();
+        store.set("c".into(), "4".into()).await.unwrap();
+        store.commit(2, vec![]).await.unwrap();
+        store.delete("a".into()).await.unwrap();
+        store.commit(3, vec![]).await.unwrap();
+
+        let all = ListOptions::default();
+        for height in 1..=3 {
+            let pairs = store.list_at(&all, height).await.unwrap();
+            assert_eq!(
+                store.root_hash_at(height).await.unwrap(),
+                merkle::root_of(&pairs).unwrap()
+            );
+        }
+
+        store.set("d".into(), "5".into()).await.unwrap();
+        let mut pairs = store.list(&all).await.unwrap();
+        assert_eq!(
+            store.root_hash().await.unwrap(),
+            merkle::root_of(&pairs).unwrap()
+        );
+        let writes = BTreeMap::from([(b"e".to_vec(), Some(b"6".to_vec()))]);
+        pairs.push((b"e".to_vec(), b"6".to_vec()));
+        assert_eq!(
+            store.root_hash_with(&writes).await.unwrap(),
+            merkle::root_of(&pairs).unwrap()
+        );
+        store.commit(4, vec![]).await.unwrap();
+
+        // Only the nodes of the latest tree are left once the others are
+        // pruned, as many as a tree built from scratch has.
+        store.prune(4, &|_| false).await.unwrap();
+        assert!(store.root_hash_at(3).await.is_err());
+        let pairs = store.list(&all).await.unwrap();
+        let changes = pairs.into_iter().map(|(k, v)| (k, Some(v))).collect();
+        let tree = merkle::update(&BTreeMap::<NodeKey, Node>::new(), None, 4, &changes).unwrap();
+        assert_eq!(store.db.scan_prefix(NODE_PREFIX).count(), tree.nodes.len());
+        assert_eq!(store.db.scan_prefix(STALE_PREFIX).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_root_hash_commits_to_values() {
+        let mut a = MemoryStore::new();
+        let mut b = MemoryStore::new();
+        a.set("key".into(), "foo".into()).await.unwrap();
+        b.set("key".into(), "bar".into()).await.unwrap();
+        assert_ne!(a.root_hash().await.unwrap(), b.root_hash().await.unwrap());
+
+        b.set("key".into(), "foo".into()).await.unwrap();
+        assert_eq!(a.root_hash().await.unwrap(), b.root_hash().await.unwrap());
+
+        let empty = MemoryStore::new().root_hash().await.unwrap();
+        a.delete("key".into()).await.unwrap();
+        assert_eq!(a.root_hash().await.unwrap(), empty);
+    }
+
+    #[tokio::test]
+    async fn test_list_through_cache() {
+        let mut base = MemoryStore::new();
+        for key in ["a/1", "a/2", "a/3", "b/1"] {
+            base.set(key.into(), key.into()).await.unwrap();
+        }
+
+        let mut cache = CacheStore::new(Arc::new(Mutex::new(base)));
+        cache.delete("a/1".into()).await.unwrap();
+        cache.set("a/4".into(), "a/4".into()).await.unwrap();
+
+        let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| {
+            pairs
+                .into_iter()
+                .map(|(k, _)| String::from_utf8(k).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let opts = ListOptions {
+            prefix: Some("a/".into()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(keys(cache.list(&opts).await.unwrap()), ["a/2", "a/3"]);
+
+        let opts = ListOptions {
+            reverse: true,
+            ..opts
+        };
+        assert_eq!(keys(cache.list(&opts).await.unwrap()), ["a/4", "a/3"]);
     }
 }